@@ -1,7 +1,22 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use clap::{Parser, Subcommand, ValueEnum};
+use indexmap::IndexMap;
 
+use thirtyfour::WebDriver;
+
+use crate::e2e_yaml::builtin::{self, Builtins};
+use crate::e2e_yaml::context::{RetryStats, RunContext};
+use crate::e2e_yaml::crypto;
+use crate::e2e_yaml::driver::Driver;
+use crate::e2e_yaml::scenario::Scenario;
+use crate::e2e_yaml::secret;
+use crate::e2e_yaml::state;
+use crate::e2e_yaml::step::Step;
+use crate::e2e_yaml::var::{VarError, Vars};
 use crate::e2e_yaml::E2eYaml;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -10,10 +25,46 @@ pub struct Args {
     #[arg(short, long, default_value = "e2e.yaml")]
     pub file: PathBuf,
 
+    /// Leaves references to undefined variables as is instead of failing.
+    #[arg(long)]
+    pub allow_undefined_vars: bool,
+
+    /// Name of the profile in `profiles` to apply.
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Overrides a global variable. Can be repeated.
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var, global = true)]
+    pub vars: Vec<(String, String)>,
+
+    /// Loads global variables from a YAML or `.env` file. Can be repeated,
+    /// later files take precedence.
+    #[arg(long = "var-file", value_name = "FILE", global = true)]
+    pub var_files: Vec<PathBuf>,
+
     #[command(subcommand)]
     pub cmd: Cmd,
 }
 
+impl Args {
+    /// Variables of the var files and `--var` options, in this order of precedence.
+    pub fn overrides(&self) -> Result<Vars, VarError> {
+        let mut overrides = Vars(IndexMap::new());
+        for path in &self.var_files {
+            overrides = overrides.merged(&Vars::load(path)?);
+        }
+        overrides.0.extend(self.vars.iter().cloned());
+        Ok(overrides)
+    }
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got '{}'", s)),
+    }
+}
+
 #[derive(Subcommand, PartialEq, Debug)]
 pub enum Cmd {
     /// Executes scenarios
@@ -21,60 +72,370 @@ pub enum Cmd {
 
     /// Print parsed e2e-yaml file
     Config(ConfigArgs),
+
+    /// Manage the encrypted secrets file
+    Secrets(SecretsArgs),
 }
 
-impl Cmd {
-    pub async fn run(&self, e2e_yaml: E2eYaml) -> Result<u8, Box<dyn std::error::Error>> {
+impl RunArgs {
+    /// Runs the selected scenarios and returns the exit code.
+    pub async fn run(
+        &self,
+        file: &Path,
+        e2e_yaml: E2eYaml,
+    ) -> Result<u8, Box<dyn std::error::Error>> {
         let mut step_err = false;
-        match self {
-            Cmd::Run(args) => {
-                let scenarios = if let Some(names) = &args.names {
-                    let names_ref: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
-                    e2e_yaml.scenarios.find(&names_ref)?
-                } else {
-                    e2e_yaml.scenarios.0.values().collect()
-                };
-
-                let driver = e2e_yaml.driver.initialize().await?;
-                for scenario in scenarios {
-                    println!("running {}", scenario.name);
-                    for step in &scenario.steps {
-                        if let Err(err) = step.run(&driver, &e2e_yaml).await {
-                            eprintln!("{}", err);
-                            step_err = true;
-                            break;
-                        };
-                    }
+        let scenarios = if let Some(names) = &self.names {
+            let names_ref: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+            e2e_yaml.scenarios.find(&names_ref)?
+        } else {
+            e2e_yaml
+                .scenarios
+                .0
+                .iter()
+                .map(|(k, v)| (k.as_str(), v))
+                .collect()
+        };
+
+        // printed so that a run with random values can be reproduced
+        let seed = self.seed.unwrap_or_else(rand::random);
+        let run_id = builtin::new_run_id(seed);
+        println!("run {} (seed {})", run_id, seed);
+
+        let snapshot_dir = file.parent().unwrap_or(Path::new("")).join("snapshots");
+        let mut retry_stats = RetryStats::default();
+        let global_vars = e2e_yaml.global_vars();
+        // the declared variables are in scope at run time too, for the
+        // references the static expansion leaves to the runtime pass
+        let new_context = |id: &str, vars: Option<&Vars>| {
+            let mut ctx = RunContext::new(
+                snapshot_dir.clone(),
+                self.artifacts_dir.clone(),
+                self.update_snapshots,
+            );
+            ctx.builtins = Builtins::new(run_id.clone(), id.to_string(), seed);
+            let vars = match vars {
+                Some(vars) => global_vars.merged(vars),
+                None => global_vars.clone(),
+            };
+            ctx.vars = vars.bind(&ctx.builtins)?;
+            Ok::<RunContext, VarError>(ctx)
+        };
+        if let Some(steps) = &e2e_yaml.before_all {
+            println!("running before_all");
+            let mut ctx = new_context("before_all", None)?;
+            let failed = match start_session(&e2e_yaml.driver, &e2e_yaml).await {
+                Some(session) => {
+                    let failed = run_steps(&session, steps, &e2e_yaml, &mut ctx).await;
+                    quit_session(session, &e2e_yaml).await || failed
                 }
-                driver.quit().await?;
+                None => true,
+            };
+            retry_stats.merge(&ctx.retry_stats);
+            if failed {
+                eprintln!("before_all failed, no scenario was run");
+                return Ok(1);
             }
-            Cmd::Config(args) => {
-                let s = if let Some(key) = &args.key {
-                    match key {
-                        ConfigSection::Driver => serde_yaml::to_string(&e2e_yaml.driver).unwrap(),
-                        ConfigSection::Vars => serde_yaml::to_string(&e2e_yaml.vars).unwrap(),
-                        ConfigSection::Tasks => serde_yaml::to_string(&e2e_yaml.tasks).unwrap(),
-                        ConfigSection::Scenarios => {
-                            serde_yaml::to_string(&e2e_yaml.scenarios).unwrap()
-                        }
+        }
+        // sessions are created on first use so that a matrix overriding
+        // the browser of every scenario does not start the default one
+        let mut shared: Option<WebDriver> = None;
+        for (id, scenario) in scenarios {
+            println!("running {}", e2e_yaml.mask(&scenario.name));
+            let mut ctx = match new_context(id, scenario.vars.as_ref()) {
+                Ok(ctx) => ctx,
+                Err(err) => {
+                    eprintln!("{}", e2e_yaml.mask(&err.to_string()));
+                    step_err = true;
+                    continue;
+                }
+            };
+            // a session that cannot be started fails the scenario only
+            let failed = if let Some(driver) = &scenario.driver {
+                match start_session(driver, &e2e_yaml).await {
+                    Some(session) => {
+                        let failed = run_scenario(&session, scenario, &e2e_yaml, &mut ctx).await;
+                        quit_session(session, &e2e_yaml).await || failed
                     }
-                } else {
-                    serde_yaml::to_string(&e2e_yaml).unwrap()
-                };
-                println!("{s}");
-            }
+                    None => true,
+                }
+            } else {
+                if shared.is_none() {
+                    shared = start_session(&e2e_yaml.driver, &e2e_yaml).await;
+                }
+                match &shared {
+                    Some(session) => run_scenario(session, scenario, &e2e_yaml, &mut ctx).await,
+                    None => true,
+                }
+            };
+            step_err |= failed;
+            retry_stats.merge(&ctx.retry_stats);
+        }
+        if let Some(session) = shared {
+            step_err |= quit_session(session, &e2e_yaml).await;
+        }
+        if retry_stats.total() > 0 {
+            println!("{}", e2e_yaml.mask(&retry_stats.to_string()));
         }
 
         Ok(step_err.into())
     }
 }
 
+impl ConfigArgs {
+    /// Prints the configuration, or one of its sections, with the secrets masked.
+    pub fn run(&self, e2e_yaml: &E2eYaml) {
+        let vars = match self.resolved {
+            true => Some(e2e_yaml.effective_vars().masked()),
+            false => e2e_yaml.vars.clone(),
+        };
+        let value = if let Some(key) = &self.key {
+            match key {
+                ConfigSection::Driver => serde_yaml::to_value(&e2e_yaml.driver),
+                ConfigSection::Vars => serde_yaml::to_value(&vars),
+                ConfigSection::Secrets => serde_yaml::to_value(&e2e_yaml.secrets),
+                ConfigSection::Profiles => serde_yaml::to_value(&e2e_yaml.profiles),
+                ConfigSection::Matrix => serde_yaml::to_value(&e2e_yaml.matrix),
+                ConfigSection::Tasks => serde_yaml::to_value(&e2e_yaml.tasks),
+                ConfigSection::BeforeAll => serde_yaml::to_value(&e2e_yaml.before_all),
+                ConfigSection::Scenarios => serde_yaml::to_value(&e2e_yaml.scenarios),
+            }
+            .unwrap()
+        } else {
+            let mut value = serde_yaml::to_value(e2e_yaml).unwrap();
+            value["vars"] = serde_yaml::to_value(&vars).unwrap();
+            value
+        };
+        // masked before serializing, as quoting would hide the secrets
+        println!("{}", e2e_yaml.mask_yaml(&value).unwrap());
+    }
+}
+
+/// Starts a browser session, reporting the error if it cannot be started.
+async fn start_session(driver: &Driver, e2e_yaml: &E2eYaml) -> Option<WebDriver> {
+    match driver.initialize().await {
+        Ok(session) => Some(session),
+        Err(err) => {
+            eprintln!(
+                "could not start a browser session. {}",
+                e2e_yaml.mask(&err.to_string())
+            );
+            None
+        }
+    }
+}
+
+/// Quits `session` and returns whether it failed, reporting the error.
+async fn quit_session(session: WebDriver, e2e_yaml: &E2eYaml) -> bool {
+    match session.quit().await {
+        Ok(()) => false,
+        Err(err) => {
+            eprintln!(
+                "could not quit the browser session. {}",
+                e2e_yaml.mask(&err.to_string())
+            );
+            true
+        }
+    }
+}
+
+/// Runs the steps of `scenario` and returns whether one of them failed.
+async fn run_scenario(
+    driver: &WebDriver,
+    scenario: &Scenario,
+    e2e_yaml: &E2eYaml,
+    ctx: &mut RunContext,
+) -> bool {
+    let load_state = scenario
+        .load_state
+        .as_ref()
+        .or(e2e_yaml.driver.load_state.as_ref());
+    if let Some(path) = load_state {
+        if let Err(err) = state::load(driver, path).await {
+            eprintln!("{}", e2e_yaml.mask(&err.to_string()));
+            return true;
+        }
+    }
+    run_steps(driver, &scenario.steps, e2e_yaml, ctx).await
+}
+
+/// Runs `steps` until one of them fails and returns whether one did.
+async fn run_steps(
+    driver: &WebDriver,
+    steps: &[Step],
+    e2e_yaml: &E2eYaml,
+    ctx: &mut RunContext,
+) -> bool {
+    for (i, step) in steps.iter().enumerate() {
+        ctx.builtins.step_index = i;
+        if let Err(err) = step.run(driver, e2e_yaml, ctx).await {
+            eprintln!("{}", e2e_yaml.mask(&err.to_string()));
+            return true;
+        };
+    }
+    false
+}
+
 #[derive(Parser, PartialEq, Debug)]
 pub struct RunArgs {
     /// Optional list of scenario names to execute.
     /// If not provided, all scenarios will be run.
     #[arg(num_args = 1..)]
     pub names: Option<Vec<String>>,
+
+    /// Overwrites the baselines of screenshot and snapshot assertions.
+    #[arg(long)]
+    pub update_snapshots: bool,
+
+    /// Directory to write the images of failed visual assertions to.
+    #[arg(long, default_value = "artifacts")]
+    pub artifacts_dir: PathBuf,
+
+    /// Seed of the random built-in variables such as `{$uuid}`.
+    /// A random one is used and printed if not provided.
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Parser, PartialEq, Debug)]
+pub struct SecretsArgs {
+    /// File holding the key. Defaults to the `E2E_SECRETS_KEY` environment variable.
+    #[arg(long, global = true)]
+    pub key_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub cmd: SecretsCmd,
+}
+
+#[derive(Subcommand, PartialEq, Debug)]
+pub enum SecretsCmd {
+    /// Print a new random key
+    Keygen,
+
+    /// Encrypt a YAML file of secrets
+    Encrypt {
+        input: PathBuf,
+        /// Writes to the file instead of the standard output.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Decrypt an encrypted secrets file
+    Decrypt {
+        input: PathBuf,
+        /// Writes to the file instead of the standard output.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Open the decrypted secrets in $VISUAL or $EDITOR and encrypt them again,
+    /// creating the file if it does not exist
+    Edit { file: PathBuf },
+}
+
+impl SecretsArgs {
+    pub fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.cmd {
+            SecretsCmd::Keygen => println!("{}", crypto::generate_key()),
+            SecretsCmd::Encrypt { input, output } => {
+                let key = self.key()?;
+                let plaintext = fs::read_to_string(input)?;
+                validate_secrets(&plaintext)?;
+                write_output(output.as_deref(), &crypto::encrypt(&plaintext, &key)?)?;
+            }
+            SecretsCmd::Decrypt { input, output } => {
+                let key = self.key()?;
+                let plaintext = crypto::decrypt(&fs::read_to_string(input)?, &key)?;
+                write_output(output.as_deref(), &plaintext)?;
+            }
+            SecretsCmd::Edit { file } => edit_secrets(file, &self.key()?)?,
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match &self.key_file {
+            Some(path) => Ok(fs::read_to_string(path)?.trim().to_string()),
+            None => std::env::var(secret::KEY_ENV)
+                .map_err(|_| format!("no key. pass --key-file or set {}", secret::KEY_ENV).into()),
+        }
+    }
+}
+
+fn validate_secrets(plaintext: &str) -> Result<(), String> {
+    serde_yaml::from_str::<Vars>(plaintext)
+        .map(|_| ())
+        .map_err(|e| format!("secrets must be a YAML mapping of names to values. {}", e))
+}
+
+fn write_output(path: Option<&Path>, contents: &str) -> std::io::Result<()> {
+    match path {
+        Some(path) => fs::write(path, contents),
+        None => {
+            print!("{}", contents);
+            Ok(())
+        }
+    }
+}
+
+/// The plaintext only lives in a temporary file readable by the user, in a new
+/// directory with an unpredictable name, removed as soon as the editor exits.
+fn edit_secrets(path: &Path, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let plaintext = match path.exists() {
+        true => crypto::decrypt(&fs::read_to_string(path)?, key)?,
+        false => String::new(),
+    };
+    // creating the directory fails if it exists, so nobody else can own it
+    let dir = std::env::temp_dir().join(format!(
+        "e2e-secrets-{}-{:016x}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&dir)?;
+    let result = edit_in(&dir, path, key, &plaintext);
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+fn edit_in(
+    dir: &Path,
+    path: &Path,
+    key: &str,
+    plaintext: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = dir.join("secrets.yaml");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(plaintext.as_bytes())?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or("vi".to_string());
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&tmp)
+        .status();
+    if !status?.success() {
+        return Err(format!("editor '{}' failed, the secrets are unchanged", editor).into());
+    }
+    let edited = fs::read_to_string(&tmp)?;
+    if edited != plaintext {
+        validate_secrets(&edited)?;
+        fs::write(path, crypto::encrypt(&edited, key)?)?;
+    }
+    Ok(())
 }
 
 #[derive(Parser, PartialEq, Debug)]
@@ -82,13 +443,22 @@ pub struct ConfigArgs {
     /// Specifies a specific configuration section to display.
     /// If omitted, the entire configuration is displayed.
     pub key: Option<ConfigSection>,
+
+    /// Shows the global variables in effect, with the overrides applied and the
+    /// references resolved. Values of sensitive names are masked.
+    #[arg(long)]
+    pub resolved: bool,
 }
 
 #[derive(ValueEnum, PartialEq, Clone, Debug)]
 pub enum ConfigSection {
     Driver,
     Vars,
+    Secrets,
+    Profiles,
+    Matrix,
     Tasks,
+    BeforeAll,
     Scenarios,
 }
 
@@ -100,13 +470,44 @@ mod cli_tests {
     fn test_parse_run() {
         let args: Args = Args::parse_from(["e2e", "run"]);
         assert_eq!(PathBuf::from("e2e.yaml"), args.file);
-        assert_eq!(Cmd::Run(RunArgs { names: None }), args.cmd);
+        assert_eq!(
+            Cmd::Run(RunArgs {
+                names: None,
+                update_snapshots: false,
+                artifacts_dir: PathBuf::from("artifacts"),
+                seed: None,
+            }),
+            args.cmd
+        );
 
         let args: Args = Args::parse_from(["e2e", "run", "s1", "s2"]);
         assert_eq!(PathBuf::from("e2e.yaml"), args.file);
         assert_eq!(
             Cmd::Run(RunArgs {
-                names: Some(vec!["s1".to_string(), "s2".to_string()])
+                names: Some(vec!["s1".to_string(), "s2".to_string()]),
+                update_snapshots: false,
+                artifacts_dir: PathBuf::from("artifacts"),
+                seed: None,
+            }),
+            args.cmd
+        );
+
+        let args: Args = Args::parse_from([
+            "e2e",
+            "run",
+            "--update-snapshots",
+            "--artifacts-dir",
+            "out",
+            "--seed",
+            "42",
+            "s1",
+        ]);
+        assert_eq!(
+            Cmd::Run(RunArgs {
+                names: Some(vec!["s1".to_string()]),
+                update_snapshots: true,
+                artifacts_dir: PathBuf::from("out"),
+                seed: Some(42),
             }),
             args.cmd
         );
@@ -116,15 +517,98 @@ mod cli_tests {
     fn test_parse_config() {
         let args: Args = Args::parse_from(["e2e", "config"]);
         assert_eq!(PathBuf::from("e2e.yaml"), args.file);
-        assert_eq!(Cmd::Config(ConfigArgs { key: None }), args.cmd);
+        assert_eq!(
+            Cmd::Config(ConfigArgs {
+                key: None,
+                resolved: false
+            }),
+            args.cmd
+        );
 
         let args: Args = Args::parse_from(["e2e", "config", "driver"]);
         assert_eq!(PathBuf::from("e2e.yaml"), args.file);
         assert_eq!(
             Cmd::Config(ConfigArgs {
-                key: Some(ConfigSection::Driver)
+                key: Some(ConfigSection::Driver),
+                resolved: false
+            }),
+            args.cmd
+        );
+
+        let args: Args = Args::parse_from(["e2e", "config", "vars", "--resolved"]);
+        assert_eq!(
+            Cmd::Config(ConfigArgs {
+                key: Some(ConfigSection::Vars),
+                resolved: true
             }),
             args.cmd
         );
     }
+
+    #[test]
+    fn test_parse_secrets() {
+        let args: Args = Args::parse_from(["e2e", "secrets", "keygen"]);
+        assert_eq!(
+            Cmd::Secrets(SecretsArgs {
+                key_file: None,
+                cmd: SecretsCmd::Keygen
+            }),
+            args.cmd
+        );
+
+        let args: Args = Args::parse_from([
+            "e2e",
+            "secrets",
+            "encrypt",
+            "secrets.yaml",
+            "-o",
+            "secrets.enc",
+            "--key-file",
+            ".e2e-key",
+        ]);
+        assert_eq!(
+            Cmd::Secrets(SecretsArgs {
+                key_file: Some(PathBuf::from(".e2e-key")),
+                cmd: SecretsCmd::Encrypt {
+                    input: PathBuf::from("secrets.yaml"),
+                    output: Some(PathBuf::from("secrets.enc"))
+                }
+            }),
+            args.cmd
+        );
+    }
+
+    #[test]
+    fn test_validate_secrets() {
+        assert!(validate_secrets("password: passw0rd\ntoken: t0ken\n").is_ok());
+        assert!(validate_secrets("- a\n- b\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_vars() {
+        let args: Args = Args::parse_from([
+            "e2e",
+            "--var-file",
+            "staging.env",
+            "run",
+            "--var",
+            "root=http://localhost:3000",
+            "--var",
+            "query=a=b",
+        ]);
+        assert_eq!(
+            vec![
+                ("root".to_string(), "http://localhost:3000".to_string()),
+                ("query".to_string(), "a=b".to_string()),
+            ],
+            args.vars
+        );
+        assert_eq!(vec![PathBuf::from("staging.env")], args.var_files);
+
+        let args: Args = Args::parse_from(["e2e", "--profile", "staging", "config"]);
+        assert_eq!(Some("staging".to_string()), args.profile);
+
+        assert!(Args::try_parse_from(["e2e", "run", "--var", "root"]).is_err());
+        assert!(Args::try_parse_from(["e2e", "run", "--var", "=x"]).is_err());
+    }
 }
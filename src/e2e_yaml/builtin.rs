@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+use super::fake;
+
+const NAMES: &[&str] = &[
+    "uuid",
+    "timestamp",
+    "date",
+    "random_int",
+    "run_id",
+    "scenario_id",
+    "step_index",
+    "fake",
+];
+
+/// Values of the `{$name:arg:...}` variables, evaluated when a step runs
+/// so that every step and scenario instance gets fresh ones.
+pub struct Builtins {
+    pub run_id: String,
+    pub scenario_id: String,
+    /// Index of the running step among the steps of the scenario.
+    /// Nested steps and the steps of tasks share the index of their top-level step.
+    pub step_index: usize,
+    rng: RefCell<StdRng>,
+}
+
+impl Default for Builtins {
+    fn default() -> Self {
+        Builtins::new(String::new(), String::new(), 0)
+    }
+}
+
+impl Builtins {
+    /// The random values are derived from `seed` and `scenario_id`, so that a scenario
+    /// gets the same values for a given seed regardless of the other scenarios run.
+    pub fn new(run_id: String, scenario_id: String, seed: u64) -> Self {
+        let rng = StdRng::seed_from_u64(seed ^ fnv1a(&scenario_id));
+        Builtins {
+            run_id,
+            scenario_id,
+            step_index: 0,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// Whether `name` with `args` can be evaluated, used to report mistakes at load time.
+    pub fn is_valid(name: &str, args: &[&str]) -> bool {
+        match name {
+            "date" => args.len() <= 1 && args.iter().all(|x| format_date(x).is_some()),
+            "random_int" => parse_range(args).is_some(),
+            "fake" => fake::is_valid(args),
+            _ => NAMES.contains(&name) && args.is_empty(),
+        }
+    }
+
+    pub fn evaluate(&self, name: &str, args: &[&str]) -> Option<String> {
+        if !Builtins::is_valid(name, args) {
+            return None;
+        }
+        let value = match name {
+            "uuid" => {
+                let mut bytes = [0u8; 16];
+                self.rng.borrow_mut().fill_bytes(&mut bytes);
+                format_uuid(bytes)
+            }
+            "timestamp" => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_millis())
+                .unwrap_or_default()
+                .to_string(),
+            "date" => format_date(args.first().unwrap_or(&"%Y-%m-%d"))?,
+            "random_int" => {
+                let (min, max) = parse_range(args)?;
+                self.rng.borrow_mut().random_range(min..=max).to_string()
+            }
+            "fake" => fake::generate(args[0], args.get(1).copied(), &mut *self.rng.borrow_mut())?,
+            "run_id" => self.run_id.to_string(),
+            "scenario_id" => self.scenario_id.to_string(),
+            "step_index" => self.step_index.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// A random version 4 UUID, used as the id of a run.
+pub fn new_run_id(seed: u64) -> String {
+    let mut bytes = [0u8; 16];
+    StdRng::seed_from_u64(seed).fill_bytes(&mut bytes);
+    format_uuid(bytes)
+}
+
+fn format_uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().fold(String::new(), |mut hex, x| {
+        let _ = write!(hex, "{:02x}", x);
+        hex
+    });
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Formats the local time, `None` if `format` holds an unknown specifier.
+fn format_date(format: &str) -> Option<String> {
+    let mut result = String::new();
+    write!(result, "{}", chrono::Local::now().format(format)).ok()?;
+    Some(result)
+}
+
+fn parse_range(args: &[&str]) -> Option<(i64, i64)> {
+    match args {
+        [min, max] => {
+            let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+            (min <= max).then_some((min, max))
+        }
+        _ => None,
+    }
+}
+
+/// A hash that does not change between builds, unlike the one of the standard library.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod builtin_tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let builtins = Builtins::new("run".to_string(), "checkout[ja]".to_string(), 1);
+        assert_eq!(Some("run".to_string()), builtins.evaluate("run_id", &[]));
+        assert_eq!(
+            Some("checkout[ja]".to_string()),
+            builtins.evaluate("scenario_id", &[])
+        );
+        assert_eq!(Some("0".to_string()), builtins.evaluate("step_index", &[]));
+
+        let n: i64 = builtins
+            .evaluate("random_int", &["-5", "5"])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((-5..=5).contains(&n));
+
+        let uuid = builtins.evaluate("uuid", &[]).unwrap();
+        assert_eq!(36, uuid.len());
+        assert_eq!(Some('4'), uuid.chars().nth(14));
+
+        let date = builtins.evaluate("date", &["%Y"]).unwrap();
+        assert_eq!(4, date.len());
+
+        assert_eq!(None, builtins.evaluate("random_int", &["5", "1"]));
+        assert_eq!(None, builtins.evaluate("date", &["%Q"]));
+        assert!(builtins
+            .evaluate("fake", &["email"])
+            .unwrap()
+            .ends_with("@example.com"));
+
+        assert_eq!(None, builtins.evaluate("uuid", &["x"]));
+        assert_eq!(None, builtins.evaluate("fake", &["name", "xx_XX"]));
+        assert_eq!(None, builtins.evaluate("nonce", &[]));
+    }
+
+    #[test]
+    fn test_seed() {
+        let values = |seed: u64, scenario_id: &str| {
+            let builtins = Builtins::new(String::new(), scenario_id.to_string(), seed);
+            (
+                builtins.evaluate("uuid", &[]),
+                builtins.evaluate("random_int", &["1", "1000000"]),
+                builtins.evaluate("fake", &["email"]),
+            )
+        };
+        assert_eq!(values(42, "s1"), values(42, "s1"));
+        assert_ne!(values(42, "s1"), values(42, "s2"));
+        assert_ne!(values(42, "s1"), values(43, "s1"));
+    }
+}
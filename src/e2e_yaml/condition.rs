@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use thirtyfour::{By, WebDriver};
+
+use super::builtin::Builtins;
+use super::step::{expand_with_defaults, StepError};
+use super::var::Vars;
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// At least one element matches the selector.
+    Exists(String),
+    /// At least one element matching the selector is displayed.
+    Visible(String),
+    /// Both values are equal after variable expansion.
+    Eq(String, String),
+    /// The JavaScript expression evaluates to a truthy value.
+    Script(String),
+    Not {
+        condition: Box<Condition>,
+    },
+}
+
+impl Condition {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: &F) -> Self {
+        match self {
+            Condition::Exists(selector) => Condition::Exists(f(selector)),
+            Condition::Visible(selector) => Condition::Visible(f(selector)),
+            Condition::Eq(left, right) => Condition::Eq(f(left), f(right)),
+            Condition::Script(script) => Condition::Script(f(script)),
+            Condition::Not { condition } => Condition::Not {
+                condition: Box::new(condition.map_strings(f)),
+            },
+        }
+    }
+
+    /// Conditions are expanded right before they are evaluated,
+    /// so missing variables fall back to their defaults.
+    pub fn expand_vars(&self, vars: &Vars, builtins: &Builtins) -> Self {
+        self.map_strings(&|x| expand_with_defaults(x, vars, builtins))
+    }
+
+    pub async fn evaluate(&self, driver: &WebDriver) -> Result<bool, StepError> {
+        let result = match self {
+            Condition::Exists(selector) => !driver.find_all(By::Css(selector)).await?.is_empty(),
+            Condition::Visible(selector) => {
+                let mut visible = false;
+                for elem in driver.find_all(By::Css(selector)).await? {
+                    if elem.is_displayed().await? {
+                        visible = true;
+                        break;
+                    }
+                }
+                visible
+            }
+            Condition::Eq(left, right) => left == right,
+            Condition::Script(script) => {
+                let ret = driver
+                    .execute(format!("return ({});", script), Vec::new())
+                    .await?;
+                is_truthy(ret.json())
+            }
+            Condition::Not { condition } => !Box::pin(condition.evaluate(driver)).await?,
+        };
+        Ok(result)
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().is_some_and(|x| x != 0.0 && !x.is_nan()),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(_) | Value::Object(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod condition_tests {
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let yaml = "
+- !exists '#banner'
+- !eq [ '{lang}', ja ]
+- !not { condition: !script 'window.ab === \"B\"' }
+";
+        let conditions: Vec<Condition> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            vec![
+                Condition::Exists("#banner".to_string()),
+                Condition::Eq("{lang}".to_string(), "ja".to_string()),
+                Condition::Not {
+                    condition: Box::new(Condition::Script("window.ab === \"B\"".to_string()))
+                },
+            ],
+            conditions
+        );
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let vars = Vars(IndexMap::from([("lang".to_string(), "ja".to_string())]));
+        let cond = Condition::Not {
+            condition: Box::new(Condition::Eq("{lang}".to_string(), "en".to_string())),
+        };
+        assert_eq!(
+            Condition::Not {
+                condition: Box::new(Condition::Eq("ja".to_string(), "en".to_string()))
+            },
+            cond.expand_vars(&vars, &Builtins::default())
+        );
+    }
+
+    #[test]
+    fn test_is_truthy() {
+        assert!(!is_truthy(&Value::Null));
+        assert!(!is_truthy(&json!(false)));
+        assert!(!is_truthy(&json!(0)));
+        assert!(!is_truthy(&json!("")));
+        assert!(is_truthy(&json!(true)));
+        assert!(is_truthy(&json!(1.5)));
+        assert!(is_truthy(&json!("0")));
+        assert!(is_truthy(&json!([])));
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use indexmap::IndexMap;
+
+use super::builtin::Builtins;
+use super::var::Vars;
+
+/// State shared by the steps of a running scenario.
+pub struct RunContext {
+    /// Variables stored by steps, expanded before each step runs.
+    pub vars: Vars,
+    /// Directory holding the baselines of `!assert_screenshot` and `!assert_snapshot`.
+    pub snapshot_dir: PathBuf,
+    /// Directory receiving the images of failed comparisons.
+    pub artifacts_dir: PathBuf,
+    /// Overwrite baselines instead of comparing against them.
+    pub update_snapshots: bool,
+    pub retry_stats: RetryStats,
+    /// Values of the `{$name}` variables.
+    pub builtins: Builtins,
+}
+
+/// Outcomes of the `!retry` blocks, kept apart from the step results so that
+/// blocks hiding flaky behavior can be spotted.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct RetryStats {
+    /// Blocks that passed on the first attempt.
+    pub passed: u64,
+    /// Blocks that passed after failing at least once.
+    pub flaky: u64,
+    /// Blocks that failed on every attempt.
+    pub failed: u64,
+}
+
+impl RetryStats {
+    pub fn merge(&mut self, other: &RetryStats) {
+        self.passed += other.passed;
+        self.flaky += other.flaky;
+        self.failed += other.failed;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.passed + self.flaky + self.failed
+    }
+}
+
+impl std::fmt::Display for RetryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "retry blocks: {} passed, {} passed after retrying, {} failed",
+            self.passed, self.flaky, self.failed
+        )
+    }
+}
+
+impl RunContext {
+    pub fn new(snapshot_dir: PathBuf, artifacts_dir: PathBuf, update_snapshots: bool) -> Self {
+        Self {
+            vars: Vars(IndexMap::new()),
+            snapshot_dir,
+            artifacts_dir,
+            update_snapshots,
+            retry_stats: RetryStats::default(),
+            builtins: Builtins::default(),
+        }
+    }
+
+    /// Records a `!retry` block that failed `failures` times out of `attempts`.
+    pub fn record_retry(&mut self, failures: u64, attempts: u64) {
+        if failures == 0 {
+            self.retry_stats.passed += 1;
+        } else if failures < attempts {
+            self.retry_stats.flaky += 1;
+        } else {
+            self.retry_stats.failed += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod context_tests {
+    use super::*;
+
+    #[test]
+    fn test_record_retry() {
+        let mut ctx = RunContext::new(PathBuf::new(), PathBuf::new(), false);
+        ctx.record_retry(0, 3);
+        ctx.record_retry(2, 3);
+        ctx.record_retry(3, 3);
+        ctx.record_retry(0, 1);
+        assert_eq!(
+            RetryStats {
+                passed: 2,
+                flaky: 1,
+                failed: 1,
+            },
+            ctx.retry_stats
+        );
+        assert_eq!(4, ctx.retry_stats.total());
+    }
+}
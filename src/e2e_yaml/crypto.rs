@@ -0,0 +1,136 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::RngCore;
+
+/// First line of an encrypted file, identifying the format.
+const HEADER: &str = "e2e-secrets:v1:aes-256-gcm";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub struct CryptoError {
+    pub kind: CryptoErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoErrorKind {
+    InvalidKey,
+    InvalidFormat,
+    /// The key is wrong or the contents were tampered with.
+    DecryptFailed,
+}
+
+impl std::error::Error for CryptoError {}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            CryptoErrorKind::InvalidKey => {
+                writeln!(f, "invalid key. expected {} base64-encoded bytes", KEY_LEN)
+            }
+            CryptoErrorKind::InvalidFormat => {
+                writeln!(f, "not an encrypted secrets file. expected '{}'", HEADER)
+            }
+            CryptoErrorKind::DecryptFailed => {
+                writeln!(f, "decryption failed. the key does not match the file")
+            }
+        }
+    }
+}
+
+/// A random key, base64-encoded.
+pub fn generate_key() -> String {
+    let mut key = [0u8; KEY_LEN];
+    rand::rng().fill_bytes(&mut key);
+    STANDARD.encode(key)
+}
+
+/// Encrypts `plaintext` into the header line followed by the base64-encoded
+/// nonce and ciphertext, so that the file can be committed as text.
+pub fn encrypt(plaintext: &str, key: &str) -> Result<String, CryptoError> {
+    let cipher = cipher(key)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .map_err(|_| CryptoError {
+            kind: CryptoErrorKind::InvalidKey,
+        })?;
+    let mut data = nonce.to_vec();
+    data.extend(ciphertext);
+    Ok(format!("{}\n{}\n", HEADER, STANDARD.encode(data)))
+}
+
+pub fn decrypt(contents: &str, key: &str) -> Result<String, CryptoError> {
+    let invalid_format = || CryptoError {
+        kind: CryptoErrorKind::InvalidFormat,
+    };
+    let cipher = cipher(key)?;
+    let data = match contents.trim().split_once('\n') {
+        Some((HEADER, data)) => STANDARD.decode(data.trim()).map_err(|_| invalid_format())?,
+        _ => return Err(invalid_format()),
+    };
+    if data.len() < NONCE_LEN {
+        return Err(invalid_format());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError {
+            kind: CryptoErrorKind::DecryptFailed,
+        })?;
+    String::from_utf8(plaintext).map_err(|_| invalid_format())
+}
+
+fn cipher(key: &str) -> Result<Aes256Gcm, CryptoError> {
+    let invalid_key = || CryptoError {
+        kind: CryptoErrorKind::InvalidKey,
+    };
+    let key = STANDARD.decode(key.trim()).map_err(|_| invalid_key())?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| invalid_key())
+}
+
+#[cfg(test)]
+mod crypto_tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = generate_key();
+        let plaintext = "password: passw0rd\napi_token: t0ken\n";
+        let encrypted = encrypt(plaintext, &key).unwrap();
+        assert!(encrypted.starts_with(HEADER));
+        assert!(!encrypted.contains("passw0rd"));
+        assert_eq!(Ok(plaintext.to_string()), decrypt(&encrypted, &key));
+        // a fresh nonce is used every time
+        assert_ne!(encrypted, encrypt(plaintext, &key).unwrap());
+    }
+
+    #[test]
+    fn test_errors() {
+        let key = generate_key();
+        let encrypted = encrypt("a: b", &key).unwrap();
+        assert_eq!(
+            Some(CryptoErrorKind::DecryptFailed),
+            decrypt(&encrypted, &generate_key()).err().map(|x| x.kind)
+        );
+        assert_eq!(
+            Some(CryptoErrorKind::InvalidKey),
+            decrypt(&encrypted, "c2hvcnQ=").err().map(|x| x.kind)
+        );
+        assert_eq!(
+            Some(CryptoErrorKind::InvalidFormat),
+            decrypt("a: b", &key).err().map(|x| x.kind)
+        );
+
+        let (header, data) = encrypted.split_once('\n').unwrap();
+        let first = if data.starts_with('A') { "B" } else { "A" };
+        let tampered = format!("{}\n{}{}", header, first, &data[1..]);
+        assert_eq!(
+            Some(CryptoErrorKind::DecryptFailed),
+            decrypt(&tampered, &key).err().map(|x| x.kind)
+        );
+    }
+}
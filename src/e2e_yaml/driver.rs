@@ -1,22 +1,126 @@
 use serde::Deserialize;
 use serde::Serialize;
-use thirtyfour::{ChromiumLikeCapabilities, DesiredCapabilities, WebDriver};
+use thirtyfour::{
+    Capabilities, CapabilitiesHelper, ChromiumLikeCapabilities, DesiredCapabilities, WebDriver,
+};
 
 use super::Window;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct Driver {
+    pub browser: Option<Browser>,
     pub host: String,
     pub port: String,
     pub headless: bool,
     pub window: Window,
+    pub unexpected_alert: Option<UnexpectedAlert>,
+    pub load_state: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Browser {
+    Edge,
+    Chrome,
+    Firefox,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnexpectedAlert {
+    Accept,
+    Dismiss,
+    Fail,
+}
+
+impl UnexpectedAlert {
+    fn prompt_behavior(&self) -> &str {
+        match self {
+            UnexpectedAlert::Accept => "accept",
+            UnexpectedAlert::Dismiss => "dismiss",
+            // leave the alert open so that the failing step can report it
+            UnexpectedAlert::Fail => "ignore",
+        }
+    }
+}
+
+/// Width and height of a named window size.
+fn window_preset(name: &str) -> Option<(u32, u32)> {
+    match name {
+        "desktop" => Some((1920, 1080)),
+        "laptop" => Some((1366, 768)),
+        "tablet" => Some((768, 1024)),
+        "mobile" => Some((375, 812)),
+        _ => None,
+    }
 }
 
 impl Driver {
+    /// Overrides the field named `key` with `value`.
+    pub fn set_field(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "browser" => {
+                self.browser = Some(
+                    serde_yaml::from_str(value)
+                        .map_err(|_| format!("unknown browser '{}'", value))?,
+                );
+            }
+            "host" => self.host = value.to_string(),
+            "port" => self.port = value.to_string(),
+            "headless" => {
+                self.headless = value
+                    .parse()
+                    .map_err(|_| format!("headless must be true or false, got '{}'", value))?;
+            }
+            "window" => {
+                let size = window_preset(value).or_else(|| {
+                    value
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+                });
+                let (width, height) = size.ok_or(format!(
+                    "window must be <WIDTH>x<HEIGHT> or one of desktop, laptop, tablet and mobile, got '{}'",
+                    value
+                ))?;
+                self.window.width = width;
+                self.window.height = height;
+            }
+            _ => {
+                return Err(format!(
+                    "unknown driver field '{}', expected one of browser, host, port, headless and window",
+                    key
+                ))
+            }
+        }
+        Ok(())
+    }
+
     pub async fn initialize(&self) -> Result<WebDriver, Box<dyn std::error::Error>> {
-        let mut caps = DesiredCapabilities::edge();
-        if self.headless {
-            caps.set_headless()?;
+        let mut caps: Capabilities = match self.browser.as_ref().unwrap_or(&Browser::Edge) {
+            Browser::Edge => {
+                let mut caps = DesiredCapabilities::edge();
+                if self.headless {
+                    caps.set_headless()?;
+                }
+                caps.into()
+            }
+            Browser::Chrome => {
+                let mut caps = DesiredCapabilities::chrome();
+                if self.headless {
+                    caps.set_headless()?;
+                }
+                caps.into()
+            }
+            Browser::Firefox => {
+                let mut caps = DesiredCapabilities::firefox();
+                if self.headless {
+                    caps.set_headless()?;
+                }
+                caps.into()
+            }
+        };
+        if let Some(policy) = &self.unexpected_alert {
+            caps.set_base_capability("unhandledPromptBehavior", policy.prompt_behavior())?;
         }
         let driver_url = format!("http://{}:{}", self.host, self.port);
         let driver = WebDriver::new(driver_url, caps).await?;
@@ -27,3 +131,36 @@ impl Driver {
         Ok(driver)
     }
 }
+
+#[cfg(test)]
+mod driver_tests {
+    use super::*;
+
+    #[test]
+    fn test_set_field() {
+        let yaml = "
+host: localhost
+port: 4444
+headless: true
+window:
+  x: 0
+  y: 0
+  width: 1920
+  height: 1080
+";
+        let mut driver: Driver = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(Ok(()), driver.set_field("browser", "firefox"));
+        assert_eq!(Ok(()), driver.set_field("headless", "false"));
+        assert_eq!(Ok(()), driver.set_field("window", "375x812"));
+        assert_eq!(Some(Browser::Firefox), driver.browser);
+        assert!(!driver.headless);
+        assert_eq!((375, 812), (driver.window.width, driver.window.height));
+        assert_eq!(Ok(()), driver.set_field("window", "tablet"));
+        assert_eq!((768, 1024), (driver.window.width, driver.window.height));
+
+        assert!(driver.set_field("browser", "netscape").is_err());
+        assert!(driver.set_field("headless", "yes").is_err());
+        assert!(driver.set_field("window", "wide").is_err());
+        assert!(driver.set_field("lang", "ja").is_err());
+    }
+}
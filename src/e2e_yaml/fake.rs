@@ -0,0 +1,286 @@
+use rand::seq::IndexedRandom;
+use rand::Rng;
+
+const KINDS: &[&str] = &[
+    "name",
+    "first_name",
+    "last_name",
+    "email",
+    "phone",
+    "address",
+    "city",
+    "zip",
+    "credit_card",
+];
+
+/// Numbers accepted by the test modes of the common payment gateways.
+const CREDIT_CARDS: &[&str] = &[
+    "4242424242424242",
+    "4111111111111111",
+    "5555555555554444",
+    "378282246310005",
+    "6011111111111117",
+    "3530111333300000",
+];
+
+/// Words of a locale. `ascii_first_names` and `ascii_last_names` are used for emails.
+struct Locale {
+    id: &'static str,
+    first_names: &'static [&'static str],
+    last_names: &'static [&'static str],
+    ascii_first_names: &'static [&'static str],
+    ascii_last_names: &'static [&'static str],
+    cities: &'static [&'static str],
+    streets: &'static [&'static str],
+}
+
+const EN_US: Locale = Locale {
+    id: "en_US",
+    first_names: &[
+        "James",
+        "Mary",
+        "John",
+        "Patricia",
+        "Robert",
+        "Jennifer",
+        "Michael",
+        "Linda",
+        "William",
+        "Elizabeth",
+    ],
+    last_names: &[
+        "Smith", "Johnson", "Williams", "Brown", "Jones", "Garcia", "Miller", "Davis", "Wilson",
+        "Taylor",
+    ],
+    ascii_first_names: &[
+        "james",
+        "mary",
+        "john",
+        "patricia",
+        "robert",
+        "jennifer",
+        "michael",
+        "linda",
+        "william",
+        "elizabeth",
+    ],
+    ascii_last_names: &[
+        "smith", "johnson", "williams", "brown", "jones", "garcia", "miller", "davis", "wilson",
+        "taylor",
+    ],
+    cities: &[
+        "Springfield",
+        "Riverside",
+        "Franklin",
+        "Greenville",
+        "Bristol",
+        "Clinton",
+        "Fairview",
+        "Salem",
+    ],
+    streets: &[
+        "Main St",
+        "Oak Ave",
+        "Maple Dr",
+        "Cedar Ln",
+        "Pine St",
+        "Elm St",
+        "Washington Ave",
+        "Lake Rd",
+    ],
+};
+
+const JA_JP: Locale = Locale {
+    id: "ja_JP",
+    first_names: &[
+        "翔太",
+        "陽菜",
+        "蓮",
+        "結衣",
+        "大翔",
+        "さくら",
+        "悠真",
+        "美咲",
+        "湊",
+        "葵",
+    ],
+    last_names: &[
+        "佐藤", "鈴木", "高橋", "田中", "伊藤", "渡辺", "山本", "中村", "小林", "加藤",
+    ],
+    ascii_first_names: &[
+        "shota", "hina", "ren", "yui", "hiroto", "sakura", "yuma", "misaki", "minato", "aoi",
+    ],
+    ascii_last_names: &[
+        "sato",
+        "suzuki",
+        "takahashi",
+        "tanaka",
+        "ito",
+        "watanabe",
+        "yamamoto",
+        "nakamura",
+        "kobayashi",
+        "kato",
+    ],
+    cities: &[
+        "東京都千代田区",
+        "大阪府大阪市",
+        "神奈川県横浜市",
+        "愛知県名古屋市",
+        "北海道札幌市",
+        "福岡県福岡市",
+    ],
+    streets: &["丸の内", "本町", "中央", "栄", "大通西", "天神"],
+};
+
+const LOCALES: &[Locale] = &[EN_US, JA_JP];
+
+/// Whether `args` is a known kind, optionally followed by a known locale.
+pub fn is_valid(args: &[&str]) -> bool {
+    match args {
+        [kind] => KINDS.contains(kind),
+        [kind, locale] => KINDS.contains(kind) && find_locale(locale).is_some(),
+        _ => false,
+    }
+}
+
+/// Generates a value of `kind` for `locale`, `en_US` if omitted.
+pub fn generate<R: Rng>(kind: &str, locale: Option<&str>, rng: &mut R) -> Option<String> {
+    let locale = find_locale(locale.unwrap_or(EN_US.id))?;
+    let value = match kind {
+        "name" => {
+            let first = pick(rng, locale.first_names);
+            let last = pick(rng, locale.last_names);
+            match locale.id {
+                "ja_JP" => format!("{} {}", last, first),
+                _ => format!("{} {}", first, last),
+            }
+        }
+        "first_name" => pick(rng, locale.first_names).to_string(),
+        "last_name" => pick(rng, locale.last_names).to_string(),
+        "email" => {
+            let first = pick(rng, locale.ascii_first_names);
+            let last = pick(rng, locale.ascii_last_names);
+            // example.com is reserved, so no mail ever reaches a real person
+            format!(
+                "{}.{}{}@example.com",
+                first,
+                last,
+                rng.random_range(1..1000)
+            )
+        }
+        "phone" => match locale.id {
+            "ja_JP" => format!(
+                "090-{:04}-{:04}",
+                rng.random_range(0..10000),
+                rng.random_range(0..10000)
+            ),
+            // 555-0100 to 555-0199 are reserved for fictional use
+            _ => format!(
+                "({}) 555-01{:02}",
+                rng.random_range(201..990),
+                rng.random_range(0..100)
+            ),
+        },
+        "address" => {
+            let city = pick(rng, locale.cities);
+            let street = pick(rng, locale.streets);
+            match locale.id {
+                "ja_JP" => format!(
+                    "{}{}{}-{}-{}",
+                    city,
+                    street,
+                    rng.random_range(1..10),
+                    rng.random_range(1..30),
+                    rng.random_range(1..20)
+                ),
+                _ => format!("{} {}, {}", rng.random_range(1..10000), street, city),
+            }
+        }
+        "city" => pick(rng, locale.cities).to_string(),
+        "zip" => match locale.id {
+            "ja_JP" => format!(
+                "{:03}-{:04}",
+                rng.random_range(0..1000),
+                rng.random_range(0..10000)
+            ),
+            _ => format!("{:05}", rng.random_range(501..100000)),
+        },
+        "credit_card" => pick(rng, CREDIT_CARDS).to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn pick<R: Rng>(rng: &mut R, words: &[&'static str]) -> &'static str {
+    words.choose(rng).copied().unwrap_or_default()
+}
+
+fn find_locale(id: &str) -> Option<&'static Locale> {
+    LOCALES.iter().find(|x| x.id == id)
+}
+
+#[cfg(test)]
+mod fake_tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// Checks the check digit of a card number.
+    fn luhn(number: &str) -> bool {
+        let sum: u32 = number
+            .chars()
+            .rev()
+            .filter_map(|x| x.to_digit(10))
+            .enumerate()
+            .map(|(i, d)| match (i % 2, d * 2) {
+                (0, _) => d,
+                (_, x) if x > 9 => x - 9,
+                (_, x) => x,
+            })
+            .sum();
+        sum % 10 == 0
+    }
+
+    #[test]
+    fn test_generate() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for kind in KINDS {
+            for locale in [None, Some("en_US"), Some("ja_JP")] {
+                let value = generate(kind, locale, &mut rng).unwrap();
+                assert!(!value.is_empty(), "{} {:?}", kind, locale);
+            }
+        }
+
+        let email = generate("email", Some("ja_JP"), &mut rng).unwrap();
+        assert!(email.is_ascii());
+        assert!(email.ends_with("@example.com"));
+
+        let name = generate("name", Some("ja_JP"), &mut rng).unwrap();
+        let (last, first) = name.split_once(' ').unwrap();
+        assert!(JA_JP.last_names.contains(&last));
+        assert!(JA_JP.first_names.contains(&first));
+
+        assert!(generate("phone", None, &mut rng)
+            .unwrap()
+            .contains(" 555-01"));
+        assert_eq!(8, generate("zip", Some("ja_JP"), &mut rng).unwrap().len());
+        for _ in 0..10 {
+            assert!(luhn(&generate("credit_card", None, &mut rng).unwrap()));
+        }
+
+        assert_eq!(None, generate("ssn", None, &mut rng));
+        assert_eq!(None, generate("name", Some("xx_XX"), &mut rng));
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid(&["email"]));
+        assert!(is_valid(&["name", "ja_JP"]));
+        assert!(!is_valid(&[]));
+        assert!(!is_valid(&["ssn"]));
+        assert!(!is_valid(&["name", "xx_XX"]));
+        assert!(!is_valid(&["name", "ja_JP", "x"]));
+    }
+}
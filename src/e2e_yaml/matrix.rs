@@ -0,0 +1,102 @@
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Values to run a scenario with, combined like a CI build matrix.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Matrix {
+    /// Extra combinations added after the product is taken.
+    pub include: Option<Vec<IndexMap<String, String>>>,
+    /// Combinations removed from the product. An entry matches every
+    /// combination having all of its values.
+    pub exclude: Option<Vec<IndexMap<String, String>>>,
+    #[serde(flatten)]
+    pub axes: IndexMap<String, Vec<String>>,
+}
+
+impl Matrix {
+    /// Returns the cartesian product of the axes, minus `exclude`, plus `include`.
+    pub fn combinations(&self) -> Vec<IndexMap<String, String>> {
+        let mut result: Vec<IndexMap<String, String>> = vec![IndexMap::new()];
+        for (key, values) in &self.axes {
+            result = result
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut c = combination.clone();
+                        c.insert(key.to_string(), value.to_string());
+                        c
+                    })
+                })
+                .collect();
+        }
+
+        if let Some(exclude) = &self.exclude {
+            result.retain(|combination| {
+                !exclude
+                    .iter()
+                    .any(|e| e.iter().all(|(k, v)| combination.get(k) == Some(v)))
+            });
+        }
+        if let Some(include) = &self.include {
+            for combination in include {
+                if !result.contains(combination) {
+                    result.push(combination.clone());
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod matrix_tests {
+    use super::*;
+
+    fn combination(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_combinations() {
+        let yaml = "
+browser: [ edge, chrome ]
+lang: [ en, ja ]
+exclude:
+  - { browser: chrome, lang: ja }
+include:
+  - { browser: firefox, lang: en }
+";
+        let matrix: Matrix = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            vec![
+                combination(&[("browser", "edge"), ("lang", "en")]),
+                combination(&[("browser", "edge"), ("lang", "ja")]),
+                combination(&[("browser", "chrome"), ("lang", "en")]),
+                combination(&[("browser", "firefox"), ("lang", "en")]),
+            ],
+            matrix.combinations()
+        );
+    }
+
+    #[test]
+    fn test_exclude_partial_match() {
+        let yaml = "
+browser: [ edge, chrome ]
+lang: [ en, ja ]
+exclude:
+  - { browser: chrome }
+";
+        let matrix: Matrix = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            vec![
+                combination(&[("browser", "edge"), ("lang", "en")]),
+                combination(&[("browser", "edge"), ("lang", "ja")]),
+            ],
+            matrix.combinations()
+        );
+    }
+}
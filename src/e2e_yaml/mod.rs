@@ -3,58 +3,284 @@ use std::io::Read;
 use std::path::Path;
 
 use driver::Driver;
-use indexmap::IndexMap;
-use scenario::Scenarios;
+use indexmap::{IndexMap, IndexSet};
+use matrix::Matrix;
+use profile::{ProfileError, ProfileErrorKind, Profiles};
+use scenario::{ScenarioError, Scenarios};
+use secret::{EncryptedSecrets, SecretError, Secrets};
 use serde::Deserialize;
 use serde::Serialize;
+use snapshot::SnapshotError;
 use step::Step;
-use task::Tasks;
-use var::Vars;
+use task::{TaskError, Tasks};
+use var::{VarError, Vars};
 
+pub mod builtin;
+pub mod condition;
+pub mod context;
+pub mod crypto;
 pub mod driver;
+pub mod fake;
+pub mod matrix;
+pub mod profile;
 pub mod scenario;
+pub mod screenshot;
+pub mod script;
+pub mod secret;
+pub mod snapshot;
+pub mod state;
 pub mod step;
+pub mod storage;
+pub mod strict;
 pub mod task;
+pub mod template;
 pub mod var;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct E2eYaml {
     pub driver: Driver,
     pub vars: Option<Vars>,
+    pub secrets: Option<Secrets>,
+    pub encrypted_secrets: Option<EncryptedSecrets>,
+    pub profiles: Option<Profiles>,
+    pub matrix: Option<Matrix>,
     pub tasks: Option<Tasks>,
+    /// Steps run once in their own session before the scenarios, e.g. to log in
+    /// and `!save_state` for the scenarios to `load_state`.
+    pub before_all: Option<Vec<Step>>,
     pub scenarios: Scenarios,
+    /// `vars` with the command-line overrides applied and the references resolved.
+    /// `vars` itself is kept as written in the file.
+    #[serde(skip)]
+    pub resolved_vars: Option<Vars>,
+    /// Values of `secrets`, never serialized.
+    #[serde(skip)]
+    pub secret_values: Option<Vars>,
 }
 
 impl E2eYaml {
-    pub fn expand(self) -> Self {
+    /// Merges the `driver` and `vars` of the profile `name` into the configuration
+    /// and drops the scenarios it does not select.
+    pub fn apply_profile(self, name: &str) -> Result<Self, ProfileError> {
+        let profile = match &self.profiles {
+            Some(profiles) => profiles.find(name)?.clone(),
+            None => {
+                return Err(ProfileError {
+                    kind: ProfileErrorKind::NotFound(name.to_string()),
+                })
+            }
+        };
+        let driver = profile
+            .merge_driver(&self.driver)
+            .map_err(|reason| ProfileError {
+                kind: ProfileErrorKind::InvalidDriver(name.to_string(), reason),
+            })?;
+        let vars = profile.merge_vars(self.vars.as_ref());
         let mut scenarios = self.scenarios;
-        let m = &mut scenarios.0;
+        scenarios.0.retain(|_, x| profile.matches(x));
+        Ok(Self {
+            driver,
+            vars,
+            scenarios,
+            ..self
+        })
+    }
+
+    /// Instantiates data-driven and matrix scenarios. Data files are resolved relative to `base_dir`.
+    pub fn instantiate(self, base_dir: &Path) -> Result<Self, ScenarioError> {
+        let scenarios = self
+            .scenarios
+            .instantiate(base_dir, &self.driver, self.matrix.as_ref())?;
+        Ok(Self { scenarios, ..self })
+    }
 
-        let default_vars = Vars(IndexMap::new());
-        let default_vars = match &self.vars {
-            Some(v) => v,
-            None => &default_vars,
+    /// Reads the values of `secrets` and decrypts `encrypted_secrets`, the former taking
+    /// precedence. Files are resolved relative to `base_dir`.
+    pub fn load_secrets(self, base_dir: &Path) -> Result<Self, SecretError> {
+        let encrypted = match &self.encrypted_secrets {
+            Some(encrypted) => Some(encrypted.load(base_dir, &var::process_env)?),
+            None => None,
+        };
+        let secret_values = match (encrypted, &self.secrets) {
+            (Some(encrypted), Some(secrets)) => {
+                Some(encrypted.merged(&secrets.load(base_dir, &var::process_env)?))
+            }
+            (Some(encrypted), None) => Some(encrypted),
+            (None, Some(secrets)) => Some(secrets.load(base_dir, &var::process_env)?),
+            (None, None) => None,
         };
+        if let Some(values) = &secret_values {
+            secret::check_lengths(values)?;
+        }
+        Ok(Self {
+            secret_values,
+            ..self
+        })
+    }
 
-        for scenario in m.values_mut() {
-            let mut steps: Vec<Step> = Vec::new();
-            scenario
-                .steps
+    /// Replaces the values of the secrets in `s` with `***`, for anything printed.
+    pub fn mask(&self, s: &str) -> String {
+        match &self.secret_values {
+            Some(values) => secret::mask(s, values),
+            None => s.to_string(),
+        }
+    }
+
+    /// Serializes `value` to YAML with the values of the secrets replaced with `***`.
+    pub fn mask_yaml<T: Serialize>(&self, value: &T) -> Result<String, serde_yaml::Error> {
+        let value = serde_yaml::to_value(value)?;
+        let value = match &self.secret_values {
+            Some(values) => secret::mask_yaml(value, values),
+            None => value,
+        };
+        serde_yaml::to_string(&value)
+    }
+
+    /// Resolves the references between variables: global `vars` may use each other and
+    /// the environment, `vars` of scenarios may also use the global ones. Task vars are
+    /// resolved when the task runs, only checked here for circular references.
+    /// `overrides` take precedence over the entries of the global `vars`.
+    pub fn resolve_vars(self, overrides: &Vars) -> Result<Self, VarError> {
+        let vars = match &self.vars {
+            Some(vars) => vars.merged(overrides),
+            None => overrides.clone(),
+        };
+        let outer = match &self.secret_values {
+            Some(secrets) => Vars::from_env().merged(secrets),
+            None => Vars::from_env(),
+        };
+        let resolved_vars = Some(vars.resolve(&outer)?);
+        let config = Self {
+            resolved_vars,
+            ..self
+        };
+        let global_vars = config.global_vars();
+
+        let mut scenarios = config.scenarios;
+        for scenario in scenarios.0.values_mut() {
+            if let Some(vars) = &scenario.vars {
+                scenario.vars = Some(vars.resolve(&global_vars)?);
+            }
+        }
+        if let Some(tasks) = &config.tasks {
+            for task in tasks.0.values() {
+                if let Some(vars) = &task.vars {
+                    vars.resolve(&Vars(IndexMap::new()))?;
+                }
+            }
+        }
+        Ok(Self {
+            scenarios,
+            ..config
+        })
+    }
+
+    pub fn expand(self) -> Self {
+        let global_vars = self.global_vars();
+        let tasks = self.tasks.as_ref();
+        // secrets and the variables using them are left to the runtime pass, so that
+        // they never appear in the configuration, and so are the names stored at run
+        // time, so that the stored values take precedence over the declared ones, and
+        // the variables holding built-ins, which are bound when the scenario starts
+        let secrets: Vec<&String> = match &self.secret_values {
+            Some(values) => values.0.values().filter(|x| !x.is_empty()).collect(),
+            None => Vec::new(),
+        };
+        let expand_steps = |steps: &[Step], vars: &Vars| -> Vec<Step> {
+            let mut runtime: IndexSet<String> = vars
+                .0
                 .iter()
-                .for_each(|x| steps.push(x.expand_vars(default_vars)));
-            scenario.steps = steps;
+                .filter(|(_, v)| secrets.iter().any(|x| v.contains(x.as_str())))
+                .map(|(k, _)| k.to_string())
+                .collect();
+            runtime.extend(vars.unbound());
+            Step::collect_stored_vars(steps, tasks, &mut runtime);
+            let vars = vars.without(&runtime);
+            steps.iter().map(|x| x.expand_vars(&vars)).collect()
+        };
+        let mut scenarios = self.scenarios;
+        let m = &mut scenarios.0;
+
+        for scenario in m.values_mut() {
+            let vars = match &scenario.vars {
+                Some(scenario_vars) => &global_vars.merged(scenario_vars),
+                None => &global_vars,
+            };
+            scenario.steps = expand_steps(&scenario.steps, vars);
         }
+        let before_all = self
+            .before_all
+            .as_ref()
+            .map(|x| expand_steps(x, &global_vars));
 
         Self {
             driver: self.driver,
             vars: self.vars,
+            profiles: self.profiles,
+            matrix: self.matrix,
             tasks: self.tasks,
+            before_all,
             scenarios,
+            resolved_vars: self.resolved_vars,
+            secrets: self.secrets,
+            encrypted_secrets: self.encrypted_secrets,
+            secret_values: self.secret_values,
+        }
+    }
+
+    /// Values of the global `vars` in effect, the resolved ones once they are resolved.
+    pub fn effective_vars(&self) -> Vars {
+        match (&self.resolved_vars, &self.vars) {
+            (Some(vars), _) | (None, Some(vars)) => vars.clone(),
+            (None, None) => Vars(IndexMap::new()),
+        }
+    }
+
+    /// Variables visible to every scenario and task, the environment overridden by
+    /// `vars`, overridden by the secrets.
+    pub fn global_vars(&self) -> Vars {
+        let vars = Vars::from_env().merged(&self.effective_vars());
+        match &self.secret_values {
+            Some(secrets) => vars.merged(secrets),
+            None => vars,
+        }
+    }
+
+    /// Checks the `!task_run` steps of every scenario and task against the task signatures.
+    pub fn validate_task_calls(&self) -> Result<(), TaskError> {
+        let tasks = self.tasks.as_ref();
+        if let Some(steps) = &self.before_all {
+            Tasks::validate_calls(tasks, "before_all", steps)?;
+        }
+        for (id, scenario) in &self.scenarios.0 {
+            Tasks::validate_calls(tasks, &format!("scenario '{}'", id), &scenario.steps)?;
+        }
+        if let Some(tasks) = tasks {
+            for (id, task) in &tasks.0 {
+                Tasks::validate_calls(Some(tasks), &format!("task '{}'", id), &task.steps)?;
+            }
         }
+        Ok(())
+    }
+
+    /// Checks that the snapshots whose default name would not be unique have a name.
+    pub fn validate_snapshot_names(&self) -> Result<(), SnapshotError> {
+        if let Some(steps) = &self.before_all {
+            snapshot::validate_names("before_all", steps, false)?;
+        }
+        for (id, scenario) in &self.scenarios.0 {
+            snapshot::validate_names(&format!("scenario '{}'", id), &scenario.steps, false)?;
+        }
+        if let Some(tasks) = &self.tasks {
+            for (id, task) in &tasks.0 {
+                snapshot::validate_names(&format!("task '{}'", id), &task.steps, true)?;
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct Window {
     pub x: i64,
     pub y: i64,
@@ -130,6 +356,12 @@ scenarios:
                 "s1".to_string(),
                 Scenario {
                     name: "first".to_string(),
+                    tags: None,
+                    load_state: None,
+                    data: None,
+                    matrix: None,
+                    vars: None,
+                    driver: None,
                     steps: vec![Step::Goto(
                         "www.google.com?search?q=hello world".to_string()
                     )]
@@ -138,4 +370,377 @@ scenarios:
             expanded_config.scenarios
         );
     }
+
+    #[test]
+    fn test_before_all() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+vars:
+  root: http://localhost
+
+tasks:
+  login:
+    arg_names: [ user ]
+    steps:
+      - !send_keys { selector: '#user', value: '{user}' }
+
+before_all:
+  - !goto '{root}/login'
+  - !task_run { id: login }
+  - !save_state { path: .auth/user.json }
+
+scenarios:
+  s1:
+    name: first
+    load_state: .auth/user.json
+    steps:
+      - !goto '{root}/'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let config = config.expand();
+        assert_eq!(
+            Some(&Step::Goto("http://localhost/login".to_string())),
+            config.before_all.as_ref().map(|x| &x[0])
+        );
+        let e = config.validate_task_calls().unwrap_err();
+        assert!(e.to_string().contains("of before_all"));
+    }
+
+    #[test]
+    fn test_expand_data() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window:
+    x: 0
+    y: 0
+    width: 1920
+    height: 1080
+
+vars:
+  root: http://localhost
+  lang: en
+
+scenarios:
+  s1:
+    name: first
+    data:
+      - { lang: ja }
+    steps:
+      - !goto '{root}/{lang}'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let expanded_config = config.instantiate(Path::new("")).unwrap().expand();
+        assert_eq!(
+            vec![Step::Goto("http://localhost/ja".to_string())],
+            expanded_config.scenarios.0["s1[ja]"].steps
+        );
+    }
+
+    #[test]
+    fn test_expand_scoped_vars() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window:
+    x: 0
+    y: 0
+    width: 1920
+    height: 1080
+
+vars:
+  root: http://localhost
+  lang: en
+  page: top
+
+scenarios:
+  staging:
+    name: staging
+    vars:
+      root: https://staging.example.com
+      lang: fr
+    data:
+      - { lang: ja }
+    steps:
+      - !goto '{root}/{lang}/{page}'
+  local:
+    name: local
+    steps:
+      - !goto '{root}/{lang}/{page}'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let expanded_config = config.instantiate(Path::new("")).unwrap().expand();
+        assert_eq!(
+            vec![Step::Goto("https://staging.example.com/ja/top".to_string())],
+            expanded_config.scenarios.0["staging[ja]"].steps
+        );
+        assert_eq!(
+            vec![Step::Goto("http://localhost/en/top".to_string())],
+            expanded_config.scenarios.0["local"].steps
+        );
+    }
+
+    #[test]
+    fn test_expand_secrets() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+scenarios:
+  s1:
+    name: first
+    vars:
+      auth: 'Bearer {password}'
+    steps:
+      - !send_keys { selector: '#password', value: '{password}' }
+      - !goto 'http://localhost/?q={password|urlencode}'
+      - !execute_script { script: 'login({password|json}, {auth|json})' }
+";
+        let mut config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        // quotes, backslashes, braces and line breaks are escaped when printed
+        let secret = "Xq\"Zw\\Vk{Jp}\nRt";
+        config.secret_values = Some(Vars(IndexMap::from([(
+            "password".to_string(),
+            secret.to_string(),
+        )])));
+        let config = config
+            .resolve_vars(&Vars(IndexMap::new()))
+            .unwrap()
+            .expand();
+        assert_eq!(
+            Step::Goto("http://localhost/?q={password|urlencode}".to_string()),
+            config.scenarios.0["s1"].steps[1]
+        );
+        let printed = config.mask_yaml(&config).unwrap();
+        for part in ["Xq", "Zw", "Vk", "Jp", "Rt"] {
+            assert!(!printed.contains(part), "{} in {}", part, printed);
+        }
+        assert!(printed.contains("Bearer ***"));
+    }
+
+    #[test]
+    fn test_expand_stored_vars() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+vars:
+  root: http://localhost
+  token: global
+
+scenarios:
+  s1:
+    name: first
+    vars:
+      page: top
+    steps:
+      - !goto '{root}/{token}'
+      - !execute_script { script: 'return 1', as: page }
+      - !get_cookie { name: session, as: token }
+      - !goto '{root}/{page}/{token}'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let config = config.expand();
+        let steps = &config.scenarios.0["s1"].steps;
+        assert_eq!(Step::Goto("http://localhost/{token}".to_string()), steps[0]);
+        assert_eq!(
+            Step::Goto("http://localhost/{page}/{token}".to_string()),
+            steps[3]
+        );
+    }
+
+    #[test]
+    fn test_expand_builtins_in_vars() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+vars:
+  email: 'u-{$uuid}@example.com'
+
+scenarios:
+  s1:
+    name: first
+    vars:
+      profile: 'http://localhost/users/{email|urlencode}?lang={lang:-en}'
+    steps:
+      - !send_keys { selector: '#email', value: '{email}' }
+      - !goto '{profile}'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let config = config
+            .resolve_vars(&Vars(IndexMap::new()))
+            .unwrap()
+            .expand();
+        let scenario = &config.scenarios.0["s1"];
+        assert_eq!(Step::Goto("{profile}".to_string()), scenario.steps[1]);
+
+        // bound when the scenario starts, so that every step gets the same value
+        let builtins = builtin::Builtins::new(String::new(), "s1".to_string(), 1);
+        let vars = config
+            .global_vars()
+            .merged(scenario.vars.as_ref().unwrap())
+            .bind(&builtins)
+            .unwrap();
+        let email = &vars.0["email"];
+        assert!(email.starts_with("u-") && email.ends_with("@example.com"));
+        assert!(!email.contains('{'));
+        let Step::SendKeys { value, .. } = &scenario.steps[0] else {
+            unreachable!()
+        };
+        assert_eq!(email, &step::expand_with_defaults(value, &vars, &builtins));
+        let Step::Goto(url) = &scenario.steps[1] else {
+            unreachable!()
+        };
+        assert_eq!(
+            format!(
+                "http://localhost/users/{}?lang=en",
+                template::urlencode(email)
+            ),
+            step::expand_with_defaults(url, &vars, &builtins)
+        );
+    }
+
+    #[test]
+    fn test_resolve_vars() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window:
+    x: 0
+    y: 0
+    width: 1920
+    height: 1080
+
+vars:
+  api: '{root}/api'
+  root: 'https://{host}'
+  host: example.com
+
+matrix:
+  lang: [ en, ja ]
+
+tasks:
+  t1:
+    vars:
+      a: '{b}'
+      b: '{a}'
+    steps:
+      - !goto '{a}'
+
+scenarios:
+  s1:
+    name: s1
+    vars:
+      top: '{root}/{lang}/'
+    steps:
+      - !goto '{top} {api}'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let mut config = config.instantiate(Path::new("")).unwrap();
+        let tasks = config.tasks.take();
+        let overrides = Vars(IndexMap::from([(
+            "host".to_string(),
+            "localhost:3000".to_string(),
+        )]));
+        let expanded_config = config.resolve_vars(&overrides).unwrap().expand();
+        assert_eq!(
+            vec![Step::Goto(
+                "https://localhost:3000/ja/ https://localhost:3000/api".to_string()
+            )],
+            expanded_config.scenarios.0["s1[ja]"].steps
+        );
+
+        let config = E2eYaml {
+            tasks,
+            ..expanded_config
+        };
+        assert_eq!(
+            Some("{root}/api".to_string()),
+            config.vars.as_ref().map(|x| x.0["api"].to_string())
+        );
+        assert_eq!(
+            "https://localhost:3000/api",
+            config.effective_vars().0["api"]
+        );
+        assert!(config.resolve_vars(&overrides).is_err());
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: false
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+vars:
+  root: http://localhost:8080
+  user: admin
+
+profiles:
+  staging:
+    driver:
+      host: selenium.staging.example.com
+      headless: true
+    vars:
+      root: https://staging.example.com
+    tags: [ smoke ]
+
+scenarios:
+  top:
+    name: top
+    tags: [ smoke ]
+    steps:
+      - !goto '{root}/'
+  checkout:
+    name: checkout
+    steps:
+      - !goto '{root}/checkout'
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let config = config.apply_profile("staging").unwrap();
+        assert_eq!("selenium.staging.example.com", config.driver.host);
+        assert!(config.driver.headless);
+        assert_eq!(1920, config.driver.window.width);
+        assert_eq!(
+            Some(Vars(IndexMap::from([
+                (
+                    "root".to_string(),
+                    "https://staging.example.com".to_string()
+                ),
+                ("user".to_string(), "admin".to_string()),
+            ]))),
+            config.vars
+        );
+        assert_eq!(vec!["top"], config.scenarios.0.keys().collect::<Vec<_>>());
+
+        assert_eq!(
+            Some(ProfileError {
+                kind: ProfileErrorKind::NotFound("prod".to_string())
+            }),
+            config.apply_profile("prod").err()
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_yaml::Value;
+
+use super::driver::Driver;
+use super::scenario::Scenario;
+use super::var::Vars;
+
+#[derive(Debug, PartialEq)]
+pub struct ProfileError {
+    pub kind: ProfileErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProfileErrorKind {
+    NotFound(String),
+    /// Holds the profile and the reason.
+    InvalidDriver(String, String),
+}
+
+impl std::error::Error for ProfileError {}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ProfileErrorKind::NotFound(name) => {
+                writeln!(f, "profile '{}' not found in configuration", name)
+            }
+            ProfileErrorKind::InvalidDriver(name, reason) => {
+                writeln!(f, "invalid driver of profile '{}'. {}", name, reason)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Profiles(pub IndexMap<String, Profile>);
+
+/// Overrides of the configuration selected with `--profile`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Profile {
+    /// Fields merged into `driver`, nested ones such as `window` included.
+    pub driver: Option<Value>,
+    pub vars: Option<Vars>,
+    /// Restricts the scenarios to the ones having one of these tags.
+    pub tags: Option<Vec<String>>,
+}
+
+impl Profiles {
+    pub fn find(&self, name: &str) -> Result<&Profile, ProfileError> {
+        self.0.get(name).ok_or(ProfileError {
+            kind: ProfileErrorKind::NotFound(name.to_string()),
+        })
+    }
+}
+
+impl Profile {
+    /// Returns `driver` with the fields of the profile deep-merged into it.
+    pub fn merge_driver(&self, driver: &Driver) -> Result<Driver, String> {
+        let overrides = match &self.driver {
+            Some(overrides) => overrides,
+            None => return Ok(driver.clone()),
+        };
+        let mut value = serde_yaml::to_value(driver).map_err(|e| e.to_string())?;
+        merge_values(&mut value, overrides);
+        serde_yaml::from_value(value).map_err(|e| e.to_string())
+    }
+
+    /// Returns `vars` with the entries of the profile taking precedence.
+    pub fn merge_vars(&self, vars: Option<&Vars>) -> Option<Vars> {
+        match (vars, &self.vars) {
+            (Some(vars), Some(overrides)) => Some(vars.merged(overrides)),
+            (vars, overrides) => vars.or(overrides.as_ref()).cloned(),
+        }
+    }
+
+    pub fn matches(&self, scenario: &Scenario) -> bool {
+        match &self.tags {
+            Some(tags) => scenario.tags.iter().flatten().any(|tag| tags.contains(tag)),
+            None => true,
+        }
+    }
+}
+
+/// Merges `overlay` into `base`. Mappings are merged key by key, anything else is replaced.
+fn merge_values(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(key) {
+                    Some(base_value) => merge_values(base_value, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_driver() {
+        let driver: Driver = serde_yaml::from_str(
+            "
+host: localhost
+port: 4444
+headless: false
+window: { x: 0, y: 0, width: 1920, height: 1080 }
+",
+        )
+        .unwrap();
+        let profile: Profile = serde_yaml::from_str(
+            "
+driver:
+  host: selenium.staging.example.com
+  headless: true
+  window: { width: 1280 }
+",
+        )
+        .unwrap();
+        let expected: Driver = serde_yaml::from_str(
+            "
+host: selenium.staging.example.com
+port: 4444
+headless: true
+window: { x: 0, y: 0, width: 1280, height: 1080 }
+",
+        )
+        .unwrap();
+        assert_eq!(Ok(expected), profile.merge_driver(&driver));
+
+        let profile: Profile = serde_yaml::from_str("driver: { window: { width: wide } }").unwrap();
+        assert!(profile.merge_driver(&driver).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let scenario = |tags: &str| -> Scenario {
+            serde_yaml::from_str(&format!("{{ name: s, tags: {}, steps: [] }}", tags)).unwrap()
+        };
+        let profile: Profile = serde_yaml::from_str("tags: [ smoke, api ]").unwrap();
+        assert!(profile.matches(&scenario("[ api ]")));
+        assert!(!profile.matches(&scenario("[ slow ]")));
+        assert!(!profile.matches(&scenario("null")));
+
+        let profile: Profile = serde_yaml::from_str("vars: { root: x }").unwrap();
+        assert!(profile.matches(&scenario("null")));
+    }
+}
@@ -1,4 +1,11 @@
+use std::fs;
+use std::path::Path;
+
+use super::driver::Driver;
+use super::matrix::Matrix;
+use super::script::json_to_var;
 use super::step::Step;
+use super::var::Vars;
 use indexmap::IndexMap;
 use serde::Deserialize;
 use serde::Serialize;
@@ -12,8 +19,17 @@ impl std::error::Error for ScenarioError {}
 
 impl std::fmt::Display for ScenarioError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.kind {
+        match &self.kind {
             ScenarioErrorKind::NotFound => write!(f, "NotFound"),
+            ScenarioErrorKind::InvalidData(id, reason) => {
+                write!(f, "invalid data of scenario '{}'. {}", id, reason)
+            }
+            ScenarioErrorKind::InvalidMatrix(id, reason) => {
+                write!(f, "invalid matrix of scenario '{}'. {}", id, reason)
+            }
+            ScenarioErrorKind::DuplicateInstance(id) => {
+                write!(f, "duplicate instance '{}'", id)
+            }
         }
     }
 }
@@ -21,38 +37,229 @@ impl std::fmt::Display for ScenarioError {
 #[derive(Debug, PartialEq)]
 pub enum ScenarioErrorKind {
     NotFound,
+    InvalidData(String, String),
+    InvalidMatrix(String, String),
+    DuplicateInstance(String),
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Scenarios(pub IndexMap<String, Scenario>);
 
 impl Scenarios {
-    pub fn find(&self, id: &[&str]) -> Result<Vec<&Scenario>, ScenarioError> {
-        let mut result: Vec<&Scenario> = Vec::new();
+    /// Finds scenarios by id and returns them with their ids.
+    /// An id of a data-driven scenario also matches all of its instances.
+    pub fn find(&self, id: &[&str]) -> Result<Vec<(&str, &Scenario)>, ScenarioError> {
+        let mut result: Vec<(&str, &Scenario)> = Vec::new();
         for ele in id {
-            if let Some(v) = self.0.get(*ele) {
-                result.push(v);
-            } else {
+            if let Some((k, v)) = self.0.get_key_value(*ele) {
+                result.push((k, v));
+                continue;
+            }
+            let prefix = format!("{}[", ele);
+            let instances: Vec<(&str, &Scenario)> = self
+                .0
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix))
+                .map(|(k, v)| (k.as_str(), v))
+                .collect();
+            if instances.is_empty() {
                 return Err(ScenarioError {
                     kind: ScenarioErrorKind::NotFound,
                 });
             }
+            result.extend(instances);
         }
         Ok(result)
     }
+
+    /// Replaces every scenario with `data` or a matrix by one instance per data row and
+    /// matrix combination, identified as `<id>[<values>]`. Matrix keys `driver.<field>`
+    /// override that field of `driver`, the others are variables. The matrix of a scenario
+    /// takes precedence over `matrix`. Data files are resolved relative to `base_dir`.
+    pub fn instantiate(
+        self,
+        base_dir: &Path,
+        driver: &Driver,
+        matrix: Option<&Matrix>,
+    ) -> Result<Self, ScenarioError> {
+        let mut result: IndexMap<String, Scenario> = IndexMap::new();
+        for (id, scenario) in self.0 {
+            let matrix = scenario.matrix.as_ref().or(matrix);
+            if scenario.data.is_none() && matrix.is_none() {
+                result.insert(id, scenario);
+                continue;
+            }
+            let rows = match &scenario.data {
+                Some(data) => data.load(base_dir).map_err(|reason| ScenarioError {
+                    kind: ScenarioErrorKind::InvalidData(id.to_string(), reason),
+                })?,
+                None => vec![IndexMap::new()],
+            };
+            let combinations = match matrix {
+                Some(matrix) => matrix.combinations(),
+                None => vec![IndexMap::new()],
+            };
+
+            let scenario_vars = scenario.vars.clone().unwrap_or(Vars(IndexMap::new()));
+            let mut i = 0;
+            for row in &rows {
+                for combination in &combinations {
+                    i += 1;
+                    let mut vars = row.clone();
+                    let mut instance_driver = driver.clone();
+                    let mut overrides_driver = false;
+                    for (key, value) in combination {
+                        match key.strip_prefix("driver.") {
+                            Some(field) => {
+                                instance_driver.set_field(field, value).map_err(|reason| {
+                                    ScenarioError {
+                                        kind: ScenarioErrorKind::InvalidMatrix(
+                                            id.to_string(),
+                                            reason,
+                                        ),
+                                    }
+                                })?;
+                                overrides_driver = true;
+                            }
+                            None => {
+                                vars.insert(key.to_string(), value.to_string());
+                            }
+                        }
+                    }
+
+                    let mut suffix = row
+                        .values()
+                        .chain(combination.values())
+                        .cloned()
+                        .collect::<Vec<String>>()
+                        .join("-");
+                    if suffix.is_empty() || result.contains_key(&format!("{}[{}]", id, suffix)) {
+                        suffix = i.to_string();
+                    }
+                    let instance_id = format!("{}[{}]", id, suffix);
+                    if result.contains_key(&instance_id) {
+                        return Err(ScenarioError {
+                            kind: ScenarioErrorKind::DuplicateInstance(instance_id),
+                        });
+                    }
+                    let instance = Scenario {
+                        name: format!("{} [{}]", scenario.name, suffix),
+                        tags: scenario.tags.clone(),
+                        load_state: scenario.load_state.clone(),
+                        data: None,
+                        matrix: None,
+                        vars: Some(scenario_vars.merged(&Vars(vars))),
+                        driver: if overrides_driver {
+                            Some(instance_driver)
+                        } else {
+                            None
+                        },
+                        steps: scenario.steps.clone(),
+                    };
+                    result.insert(instance_id, instance);
+                }
+            }
+        }
+        Ok(Scenarios(result))
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Scenario {
     pub name: String,
+    /// Labels to select scenarios by, e.g. with a profile.
+    pub tags: Option<Vec<String>>,
+    /// Variables overriding the global ones. An instance of a data-driven or matrix
+    /// scenario also holds the values of its data row and matrix combination.
+    pub vars: Option<Vars>,
+    pub load_state: Option<String>,
+    pub data: Option<Data>,
+    pub matrix: Option<Matrix>,
+    /// Driver of a matrix combination overriding `driver`, run in its own session.
+    #[serde(skip)]
+    pub driver: Option<Driver>,
     pub steps: Vec<Step>,
 }
 
+/// Rows of a data-driven scenario, either inline or a path to a CSV or JSON file.
+/// Non-string values of inline rows are stored as JSON, like those of a JSON file.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Data {
+    Rows(Vec<IndexMap<String, serde_json::Value>>),
+    File(String),
+}
+
+impl Data {
+    fn load(&self, base_dir: &Path) -> Result<Vec<IndexMap<String, String>>, String> {
+        let path = match self {
+            Data::Rows(rows) => return Ok(rows.iter().map(row_to_vars).collect()),
+            Data::File(path) => base_dir.join(path),
+        };
+        let contents =
+            fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("csv") => parse_csv(&contents),
+            Some("json") => parse_json(&contents),
+            _ => Err(format!(
+                "{}: data file must be a .csv or .json file",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// The header row holds the variable names.
+fn parse_csv(contents: &str) -> Result<Vec<IndexMap<String, String>>, String> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        rows.push(
+            headers
+                .iter()
+                .zip(record.iter())
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+    }
+    Ok(rows)
+}
+
+/// An array of objects, non-string values are stored as JSON.
+fn parse_json(contents: &str) -> Result<Vec<IndexMap<String, String>>, String> {
+    let rows: Vec<IndexMap<String, serde_json::Value>> =
+        serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    Ok(rows.iter().map(row_to_vars).collect())
+}
+
+fn row_to_vars(row: &IndexMap<String, serde_json::Value>) -> IndexMap<String, String> {
+    row.iter()
+        .map(|(k, v)| (k.clone(), json_to_var(v)))
+        .collect()
+}
+
 #[cfg(test)]
 mod scenario_tests {
 
+    use super::super::driver::Browser;
     use super::*;
 
+    fn test_driver() -> Driver {
+        let yaml = "
+host: localhost
+port: 4444
+headless: true
+window:
+  x: 0
+  y: 0
+  width: 1920
+  height: 1080
+";
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
     #[test]
     fn test_find() {
         let yaml = "
@@ -88,17 +295,29 @@ id5:
         let expected = [
             Scenario {
                 name: "name1".to_string(),
+                tags: None,
+                load_state: None,
+                data: None,
+                matrix: None,
+                vars: None,
+                driver: None,
                 steps: vec![Step::Goto("http://localhost".to_string())],
             },
             Scenario {
                 name: "name5".to_string(),
+                tags: None,
+                load_state: None,
+                data: None,
+                matrix: None,
+                vars: None,
+                driver: None,
                 steps: vec![Step::Goto("http://localhost".to_string())],
             },
         ];
         let actual = scenarios.find(id.as_slice()).unwrap();
         assert_eq!(2, actual.len());
-        assert_eq!(&expected[0], actual[0]);
-        assert_eq!(&expected[1], actual[1]);
+        assert_eq!(("id1", &expected[0]), actual[0]);
+        assert_eq!(("id5", &expected[1]), actual[1]);
     }
 
     #[test]
@@ -143,4 +362,237 @@ id5:
             err.unwrap()
         );
     }
+
+    #[test]
+    fn test_expand_data() {
+        let yaml = "
+checkout:
+  name: checkout
+  data:
+    - { locale: jp, role: admin }
+    - { locale: en, role: guest }
+  steps:
+    - !goto 'http://localhost/{locale}'
+
+login:
+  name: login
+  steps:
+    - !goto 'http://localhost'
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let scenarios = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .unwrap();
+        let ids: Vec<&str> = scenarios.0.keys().map(|x| x.as_str()).collect();
+        assert_eq!(
+            vec!["checkout[jp-admin]", "checkout[en-guest]", "login"],
+            ids
+        );
+
+        let instance = &scenarios.0["checkout[jp-admin]"];
+        assert_eq!("checkout [jp-admin]", instance.name);
+        assert_eq!(None, instance.data);
+        assert_eq!(
+            Some(Vars(IndexMap::from([
+                ("locale".to_string(), "jp".to_string()),
+                ("role".to_string(), "admin".to_string()),
+            ]))),
+            instance.vars
+        );
+
+        let found = scenarios.find(&["checkout"]).unwrap();
+        assert_eq!(2, found.len());
+        let found = scenarios.find(&["checkout[en-guest]"]).unwrap();
+        assert_eq!("checkout [en-guest]", found[0].1.name);
+    }
+
+    #[test]
+    fn test_expand_data_scalars() {
+        let yaml = "
+checkout:
+  name: checkout
+  data:
+    - { quantity: 2, gift: true, price: 9.5 }
+  steps: []
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let scenarios = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .unwrap();
+        let instance = &scenarios.0["checkout[2-true-9.5]"];
+        assert_eq!(
+            Some(Vars(IndexMap::from([
+                ("quantity".to_string(), "2".to_string()),
+                ("gift".to_string(), "true".to_string()),
+                ("price".to_string(), "9.5".to_string()),
+            ]))),
+            instance.vars
+        );
+    }
+
+    #[test]
+    fn test_instantiate_duplicate() {
+        let yaml = "
+checkout:
+  name: checkout
+  data:
+    - { role: '3' }
+    - { role: admin }
+    - { role: admin }
+  steps: []
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let err = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .err();
+        assert_eq!(
+            Some(ScenarioError {
+                kind: ScenarioErrorKind::DuplicateInstance("checkout[3]".to_string())
+            }),
+            err
+        );
+    }
+
+    #[test]
+    fn test_instantiate_matrix() {
+        let yaml = "
+checkout:
+  name: checkout
+  data:
+    - { role: admin }
+  matrix:
+    driver.browser: [ edge, chrome ]
+    lang: [ en ]
+  steps:
+    - !goto 'http://localhost/{lang}'
+
+login:
+  name: login
+  steps:
+    - !goto 'http://localhost'
+";
+        let matrix: Matrix = serde_yaml::from_str("driver.window: [ 1920x1080, 375x812 ]").unwrap();
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let scenarios = scenarios
+            .instantiate(Path::new(""), &test_driver(), Some(&matrix))
+            .unwrap();
+        let ids: Vec<&str> = scenarios.0.keys().map(|x| x.as_str()).collect();
+        assert_eq!(
+            vec![
+                "checkout[admin-edge-en]",
+                "checkout[admin-chrome-en]",
+                "login[1920x1080]",
+                "login[375x812]",
+            ],
+            ids
+        );
+
+        let instance = &scenarios.0["checkout[admin-chrome-en]"];
+        assert_eq!(
+            Some(Vars(IndexMap::from([
+                ("role".to_string(), "admin".to_string()),
+                ("lang".to_string(), "en".to_string()),
+            ]))),
+            instance.vars
+        );
+        let driver = instance.driver.as_ref().unwrap();
+        assert_eq!(Some(Browser::Chrome), driver.browser);
+
+        let instance = &scenarios.0["login[375x812]"];
+        assert_eq!(Some(Vars(IndexMap::new())), instance.vars);
+        let driver = instance.driver.as_ref().unwrap();
+        assert_eq!((375, 812), (driver.window.width, driver.window.height));
+    }
+
+    #[test]
+    fn test_instantiate_invalid_matrix() {
+        let yaml = "
+s1:
+  name: s1
+  matrix:
+    headless: [ maybe ]
+  steps: []
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let scenarios = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .unwrap();
+        let instance = &scenarios.0["s1[maybe]"];
+        assert_eq!(None, instance.driver);
+
+        let yaml = "
+s1:
+  name: s1
+  matrix:
+    driver.headless: [ maybe ]
+  steps: []
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let err = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .err();
+        assert!(matches!(
+            err,
+            Some(ScenarioError {
+                kind: ScenarioErrorKind::InvalidMatrix(_, _)
+            })
+        ));
+
+        let yaml = "
+s1:
+  name: s1
+  matrix:
+    driver.lang: [ ja ]
+  steps: []
+";
+        let scenarios: Scenarios = serde_yaml::from_str(yaml).unwrap();
+        let err = scenarios
+            .instantiate(Path::new(""), &test_driver(), None)
+            .err();
+        assert!(matches!(
+            err,
+            Some(ScenarioError {
+                kind: ScenarioErrorKind::InvalidMatrix(_, _)
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "locale,role\njp,admin\n\"en,us\",guest\n";
+        let rows = parse_csv(csv).unwrap();
+        assert_eq!(
+            vec![
+                IndexMap::from([
+                    ("locale".to_string(), "jp".to_string()),
+                    ("role".to_string(), "admin".to_string()),
+                ]),
+                IndexMap::from([
+                    ("locale".to_string(), "en,us".to_string()),
+                    ("role".to_string(), "guest".to_string()),
+                ]),
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let json = r#"[ { "locale": "jp", "age": 20 }, { "locale": "en", "age": null } ]"#;
+        let rows = parse_json(json).unwrap();
+        assert_eq!(
+            vec![
+                IndexMap::from([
+                    ("locale".to_string(), "jp".to_string()),
+                    ("age".to_string(), "20".to_string()),
+                ]),
+                IndexMap::from([
+                    ("locale".to_string(), "en".to_string()),
+                    ("age".to_string(), "null".to_string()),
+                ]),
+            ],
+            rows
+        );
+        assert!(parse_json("{}").is_err());
+    }
 }
@@ -0,0 +1,479 @@
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use http::Method;
+use image::{Rgba, RgbaImage};
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::{json, Value};
+use thirtyfour::common::command::FormatRequestData;
+use thirtyfour::extensions::addons::firefox::FirefoxTools;
+use thirtyfour::{By, RequestData, SessionId, WebDriver};
+
+use super::context::RunContext;
+use super::step::{StepError, StepErrorKind};
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(from = "ScreenShotRepr")]
+pub struct ScreenShot {
+    pub path: String,
+    pub selector: Option<String>,
+    pub full_page: bool,
+    pub mask: Option<Vec<String>>,
+}
+
+/// `!screen_shot` accepts either a bare path or a map of options.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScreenShotRepr {
+    Path(String),
+    Options {
+        path: String,
+        selector: Option<String>,
+        #[serde(default)]
+        full_page: bool,
+        mask: Option<Vec<String>>,
+    },
+}
+
+impl From<ScreenShotRepr> for ScreenShot {
+    fn from(repr: ScreenShotRepr) -> Self {
+        match repr {
+            ScreenShotRepr::Path(path) => ScreenShot {
+                path,
+                selector: None,
+                full_page: false,
+                mask: None,
+            },
+            ScreenShotRepr::Options {
+                path,
+                selector,
+                full_page,
+                mask,
+            } => ScreenShot {
+                path,
+                selector,
+                full_page,
+                mask,
+            },
+        }
+    }
+}
+
+impl ScreenShot {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        ScreenShot {
+            path: f(&self.path),
+            selector: self.selector.as_ref().map(|x| f(x)),
+            full_page: self.full_page,
+            mask: self.mask.as_ref().map(|x| x.iter().map(|y| f(y)).collect()),
+        }
+    }
+
+    pub async fn run(&self, driver: &WebDriver) -> Result<(), StepError> {
+        let img = capture(
+            driver,
+            self.selector.as_deref(),
+            self.full_page,
+            self.mask.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        save_png(&img, &self.path)
+    }
+}
+
+/// Compares a capture with the baseline `<snapshot_dir>/<name>.png`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct ScreenShotAssertion {
+    pub name: String,
+    pub selector: Option<String>,
+    #[serde(default)]
+    pub full_page: bool,
+    pub mask: Option<Vec<String>>,
+    pub threshold: f64,
+}
+
+impl ScreenShotAssertion {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        ScreenShotAssertion {
+            name: f(&self.name),
+            selector: self.selector.as_ref().map(|x| f(x)),
+            full_page: self.full_page,
+            mask: self.mask.as_ref().map(|x| x.iter().map(|y| f(y)).collect()),
+            threshold: self.threshold,
+        }
+    }
+
+    pub async fn run(&self, driver: &WebDriver, ctx: &RunContext) -> Result<(), StepError> {
+        let actual = capture(
+            driver,
+            self.selector.as_deref(),
+            self.full_page,
+            self.mask.as_deref().unwrap_or_default(),
+        )
+        .await?;
+        let baseline = ctx.snapshot_dir.join(format!("{}.png", self.name));
+        if ctx.update_snapshots || !baseline.exists() {
+            println!("\twriting baseline {}", baseline.display());
+            return save_png(&actual, &baseline);
+        }
+
+        let expected = image::open(&baseline)?.to_rgba8();
+        let (ratio, diff) = compare(&expected, &actual);
+        if ratio > self.threshold {
+            let mut artifacts: Vec<String> = Vec::new();
+            for (suffix, img) in [
+                ("expected", &expected),
+                ("actual", &actual),
+                ("diff", &diff),
+            ] {
+                let path = ctx
+                    .artifacts_dir
+                    .join(format!("{}-{}.png", self.name, suffix));
+                save_png(img, &path)?;
+                artifacts.push(path.display().to_string());
+            }
+            return Err(StepError {
+                kind: StepErrorKind::ScreenshotMismatch(
+                    self.name.to_string(),
+                    ratio,
+                    self.threshold,
+                    artifacts,
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the ratio of differing pixels and an image marking them in red.
+/// Pixels outside of either image count as differing.
+pub fn compare(expected: &RgbaImage, actual: &RgbaImage) -> (f64, RgbaImage) {
+    let width = expected.width().max(actual.width());
+    let height = expected.height().max(actual.height());
+    let mut diff = RgbaImage::new(width, height);
+    let mut count: u64 = 0;
+    for (x, y, p) in diff.enumerate_pixels_mut() {
+        let e = expected.get_pixel_checked(x, y);
+        let a = actual.get_pixel_checked(x, y);
+        *p = match (e, a) {
+            (Some(e), Some(a)) if e == a => {
+                // faded copy of the page to give context to the marks
+                let [r, g, b, _] = a.0;
+                Rgba([r / 4 + 191, g / 4 + 191, b / 4 + 191, 255])
+            }
+            _ => {
+                count += 1;
+                Rgba([255, 0, 0, 255])
+            }
+        };
+    }
+    let total = width as u64 * height as u64;
+    if total == 0 {
+        return (0.0, diff);
+    }
+    (count as f64 / total as f64, diff)
+}
+
+/// Saves `img` to `path`, creating the parent directories if needed.
+pub fn save_png<P: AsRef<Path>>(img: &RgbaImage, path: P) -> Result<(), StepError> {
+    let p = path.as_ref();
+    if let Some(dir) = p.parent() {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    img.save_with_format(p, image::ImageFormat::Png)?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Viewport {
+    device_pixel_ratio: f64,
+    scroll_x: f64,
+    scroll_y: f64,
+    height: f64,
+    page_height: f64,
+}
+
+async fn viewport(driver: &WebDriver) -> Result<Viewport, StepError> {
+    let ret = driver
+        .execute(
+            r#"
+            return {
+                devicePixelRatio: window.devicePixelRatio,
+                scrollX: window.scrollX,
+                scrollY: window.scrollY,
+                height: window.innerHeight,
+                pageHeight: document.documentElement.scrollHeight,
+            };
+            "#,
+            Vec::new(),
+        )
+        .await?;
+    Ok(ret.convert()?)
+}
+
+/// Captures the viewport, the element matching `selector` or the whole page
+/// and blacks out the elements matching any of `mask`.
+pub async fn capture(
+    driver: &WebDriver,
+    selector: Option<&str>,
+    full_page: bool,
+    mask: &[String],
+) -> Result<RgbaImage, StepError> {
+    let vp = viewport(driver).await?;
+    let (mut img, origin_x, origin_y) = if let Some(selector) = selector {
+        let elem = driver.find(By::Css(selector)).await?;
+        let png = elem.screenshot_as_png().await?;
+        let rect = elem.rect().await?;
+        (decode_png(&png)?, rect.x, rect.y)
+    } else if full_page {
+        (capture_full_page(driver, &vp).await?, 0.0, 0.0)
+    } else {
+        let png = driver.screenshot_as_png().await?;
+        (decode_png(&png)?, vp.scroll_x, vp.scroll_y)
+    };
+
+    let dpr = vp.device_pixel_ratio;
+    for selector in mask {
+        for elem in driver.find_all(By::Css(selector)).await? {
+            let rect = elem.rect().await?;
+            fill_black(
+                &mut img,
+                ((rect.x - origin_x) * dpr).floor() as i64,
+                ((rect.y - origin_y) * dpr).floor() as i64,
+                (rect.width * dpr).ceil() as i64,
+                (rect.height * dpr).ceil() as i64,
+            );
+        }
+    }
+    Ok(img)
+}
+
+/// Captures the whole page natively, through the DevTools protocol of Chromium based
+/// browsers or the full-page screenshot of Firefox. Browsers supporting neither are
+/// captured by stitching.
+async fn capture_full_page(driver: &WebDriver, vp: &Viewport) -> Result<RgbaImage, StepError> {
+    for vendor in ["goog", "ms"] {
+        if let Ok(png) = capture_full_page_cdp(driver, vendor).await {
+            return decode_png(&png);
+        }
+    }
+    let firefox = FirefoxTools::new(driver.handle.clone());
+    if let Ok(png) = firefox.full_screenshot_as_png().await {
+        return decode_png(&png);
+    }
+    capture_full_page_stitched(driver, vp).await
+}
+
+/// A DevTools protocol command, sent to the endpoint of the vendor of the driver,
+/// `goog` for Chrome and `ms` for Edge.
+#[derive(Debug)]
+struct CdpCommand {
+    vendor: &'static str,
+    cmd: &'static str,
+    params: Value,
+}
+
+impl FormatRequestData for CdpCommand {
+    fn format_request(&self, session_id: &SessionId) -> RequestData {
+        RequestData::new(
+            Method::POST,
+            format!("/session/{}/{}/cdp/execute", session_id, self.vendor),
+        )
+        .add_body(json!({ "cmd": self.cmd, "params": self.params }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayoutMetrics {
+    css_content_size: ContentSize,
+}
+
+#[derive(Deserialize)]
+struct ContentSize {
+    width: f64,
+    height: f64,
+}
+
+#[derive(Deserialize)]
+struct CdpScreenshot {
+    data: String,
+}
+
+/// Captures the whole page with `captureBeyondViewport`, returning the PNG bytes.
+async fn capture_full_page_cdp(
+    driver: &WebDriver,
+    vendor: &'static str,
+) -> Result<Vec<u8>, String> {
+    let execute = |cmd: &'static str, params: Value| {
+        driver.handle.cmd(CdpCommand {
+            vendor,
+            cmd,
+            params,
+        })
+    };
+    let metrics: LayoutMetrics = execute("Page.getLayoutMetrics", json!({}))
+        .await
+        .and_then(|x| x.value())
+        .map_err(|e| e.to_string())?;
+    let size = metrics.css_content_size;
+    let params = json!({
+        "format": "png",
+        "captureBeyondViewport": true,
+        "clip": { "x": 0, "y": 0, "width": size.width, "height": size.height, "scale": 1 },
+    });
+    let shot: CdpScreenshot = execute("Page.captureScreenshot", params)
+        .await
+        .and_then(|x| x.value())
+        .map_err(|e| e.to_string())?;
+    STANDARD.decode(shot.data).map_err(|e| e.to_string())
+}
+
+/// Scrolls the page one viewport at a time and stitches the captures.
+async fn capture_full_page_stitched(
+    driver: &WebDriver,
+    vp: &Viewport,
+) -> Result<RgbaImage, StepError> {
+    let dpr = vp.device_pixel_ratio;
+    let mut canvas: Option<RgbaImage> = None;
+    let mut y = 0.0;
+    loop {
+        let ret = driver
+            .execute(
+                "window.scrollTo(window.scrollX, arguments[0]); return window.scrollY;",
+                vec![Value::from(y)],
+            )
+            .await?;
+        let actual_y: f64 = ret.convert()?;
+        let shot = decode_png(&driver.screenshot_as_png().await?)?;
+        let c = canvas.get_or_insert_with(|| {
+            RgbaImage::new(shot.width(), (vp.page_height * dpr).ceil() as u32)
+        });
+        image::imageops::replace(c, &shot, 0, (actual_y * dpr).round() as i64);
+        // the last capture is clamped to the bottom of the page
+        if actual_y + vp.height >= vp.page_height || actual_y < y {
+            break;
+        }
+        y += vp.height;
+    }
+    driver
+        .execute(
+            "window.scrollTo(arguments[0], arguments[1]);",
+            vec![Value::from(vp.scroll_x), Value::from(vp.scroll_y)],
+        )
+        .await?;
+    Ok(canvas.unwrap_or_default())
+}
+
+pub fn decode_png(png: &[u8]) -> Result<RgbaImage, StepError> {
+    let img = image::load_from_memory_with_format(png, image::ImageFormat::Png)?;
+    Ok(img.to_rgba8())
+}
+
+/// Fills the given region with black, clipping it to the image bounds.
+pub fn fill_black(img: &mut RgbaImage, x: i64, y: i64, width: i64, height: i64) {
+    let x0 = x.clamp(0, img.width() as i64) as u32;
+    let y0 = y.clamp(0, img.height() as i64) as u32;
+    let x1 = (x + width).clamp(0, img.width() as i64) as u32;
+    let y1 = (y + height).clamp(0, img.height() as i64) as u32;
+    for py in y0..y1 {
+        for px in x0..x1 {
+            img.put_pixel(px, py, Rgba([0, 0, 0, 255]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod screenshot_tests {
+    use crate::e2e_yaml::step::Step;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let yaml = "
+- !screen_shot 'img/a.png'
+- !screen_shot { path: 'img/b.png', selector: '#main', full_page: true, mask: [ '.time' ] }
+";
+        let steps: Vec<Step> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            Step::ScreenShot(ScreenShot {
+                path: "img/a.png".to_string(),
+                selector: None,
+                full_page: false,
+                mask: None,
+            }),
+            steps[0]
+        );
+        assert_eq!(
+            Step::ScreenShot(ScreenShot {
+                path: "img/b.png".to_string(),
+                selector: Some("#main".to_string()),
+                full_page: true,
+                mask: Some(vec![".time".to_string()]),
+            }),
+            steps[1]
+        );
+    }
+
+    #[test]
+    fn test_parse_viewport() {
+        let value = json!({
+            "devicePixelRatio": 2,
+            "scrollX": 0,
+            "scrollY": 120.5,
+            "height": 800,
+            "pageHeight": 3000,
+        });
+        assert_eq!(
+            Viewport {
+                device_pixel_ratio: 2.0,
+                scroll_x: 0.0,
+                scroll_y: 120.5,
+                height: 800.0,
+                page_height: 3000.0,
+            },
+            serde_json::from_value(value).unwrap()
+        );
+        assert!(serde_json::from_value::<Viewport>(json!([2, 0, 120.5])).is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        let mut actual = expected.clone();
+        actual.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        let (ratio, diff) = compare(&expected, &actual);
+        assert_eq!(0.25, ratio);
+        assert_eq!(Rgba([255, 0, 0, 255]), *diff.get_pixel(1, 0));
+        assert_eq!(Rgba([254, 254, 254, 255]), *diff.get_pixel(0, 0));
+
+        let (ratio, _) = compare(&expected, &expected);
+        assert_eq!(0.0, ratio);
+    }
+
+    #[test]
+    fn test_compare_size_mismatch() {
+        let expected = RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 255]));
+        let actual = RgbaImage::from_pixel(2, 1, Rgba([255, 255, 255, 255]));
+        let (ratio, diff) = compare(&expected, &actual);
+        assert_eq!(0.5, ratio);
+        assert_eq!((2, 2), diff.dimensions());
+    }
+
+    #[test]
+    fn test_fill_black() {
+        let mut img = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        fill_black(&mut img, -1, 2, 3, 5);
+        for (x, y, p) in img.enumerate_pixels() {
+            let masked = x < 2 && y >= 2;
+            assert_eq!(masked, *p == Rgba([0, 0, 0, 255]), "pixel ({}, {})", x, y);
+        }
+    }
+}
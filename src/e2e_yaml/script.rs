@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use thirtyfour::error::WebDriverErrorInner;
+use thirtyfour::By;
+
+use super::step::{StepError, StepErrorKind};
+use super::var::Vars;
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Script {
+    pub script: String,
+    pub args: Option<Vec<ScriptArg>>,
+    #[serde(rename = "as")]
+    pub store_as: Option<String>,
+    pub expected: Option<Value>,
+}
+
+/// An argument passed to a script. A map with a single `selector` key is
+/// resolved to the matching element, anything else is passed as JSON.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ScriptArg {
+    Element { selector: String },
+    Value(Value),
+}
+
+impl Script {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        Script {
+            script: f(&self.script),
+            args: self.args.as_ref().map(|args| {
+                args.iter()
+                    .map(|arg| match arg {
+                        ScriptArg::Element { selector } => ScriptArg::Element {
+                            selector: f(selector),
+                        },
+                        ScriptArg::Value(v) => ScriptArg::Value(map_json_strings(v, &f)),
+                    })
+                    .collect()
+            }),
+            store_as: self.store_as.clone(),
+            expected: self.expected.as_ref().map(|v| map_json_strings(v, &f)),
+        }
+    }
+
+    pub async fn execute(
+        &self,
+        driver: &thirtyfour::WebDriver,
+        vars: &mut Vars,
+        is_async: bool,
+    ) -> Result<(), StepError> {
+        let mut args: Vec<Value> = Vec::new();
+        for arg in self.args.iter().flatten() {
+            match arg {
+                ScriptArg::Element { selector } => {
+                    let elem = driver.find(By::Css(selector)).await?;
+                    args.push(elem.to_json()?);
+                }
+                ScriptArg::Value(v) => args.push(v.clone()),
+            }
+        }
+        let ret = if is_async {
+            driver.execute_async(self.script.as_str(), args).await
+        } else {
+            driver.execute(self.script.as_str(), args).await
+        };
+        let ret = ret.map_err(|e| match e.as_inner() {
+            WebDriverErrorInner::JavascriptError(info) => StepError {
+                kind: StepErrorKind::ScriptFailed(
+                    info.value.message.clone(),
+                    info.value.stacktrace.clone().unwrap_or_default(),
+                ),
+            },
+            _ => e.into(),
+        })?;
+        let actual = ret.json();
+        if let Some(expected) = &self.expected {
+            if expected != actual {
+                return Err(StepError {
+                    kind: StepErrorKind::AssertFailed(expected.to_string(), actual.to_string()),
+                });
+            }
+        }
+        if let Some(name) = &self.store_as {
+            vars.0.insert(name.to_string(), json_to_var(actual));
+        }
+        Ok(())
+    }
+}
+
+/// Converts a script result into a variable value. Strings are stored as is,
+/// everything else as its JSON representation.
+pub fn json_to_var(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.to_string(),
+        _ => value.to_string(),
+    }
+}
+
+fn map_json_strings<F: Fn(&str) -> String>(value: &Value, f: &F) -> Value {
+    match value {
+        Value::String(s) => Value::String(f(s)),
+        Value::Array(a) => Value::Array(a.iter().map(|x| map_json_strings(x, f)).collect()),
+        Value::Object(o) => Value::Object(
+            o.iter()
+                .map(|(k, v)| (k.clone(), map_json_strings(v, f)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod script_tests {
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    use super::*;
+    use crate::e2e_yaml::step::expand;
+
+    #[test]
+    fn test_parse_args() {
+        let yaml = "
+script: 'return arguments[0].value + arguments[1]'
+args:
+  - selector: '#input'
+  - 42
+  - { a: b }
+as: result
+";
+        let script: Script = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            Some(vec![
+                ScriptArg::Element {
+                    selector: "#input".to_string()
+                },
+                ScriptArg::Value(json!(42)),
+                ScriptArg::Value(json!({ "a": "b" })),
+            ]),
+            script.args
+        );
+        assert_eq!(Some("result".to_string()), script.store_as);
+        assert_eq!(None, script.expected);
+    }
+
+    #[test]
+    fn test_expand_vars() {
+        let yaml = "
+script: 'return {app}'
+args:
+  - selector: '{app}'
+  - [ '{app}', 1 ]
+expected: { name: '{app}' }
+";
+        let vars = Vars(IndexMap::from([("app".to_string(), "e2e".to_string())]));
+        let script: Script = serde_yaml::from_str(yaml).unwrap();
+        let expanded = script.map_strings(|x| expand(x, &vars));
+        assert_eq!("return e2e", expanded.script);
+        assert_eq!(
+            Some(vec![
+                ScriptArg::Element {
+                    selector: "e2e".to_string()
+                },
+                ScriptArg::Value(json!(["e2e", 1])),
+            ]),
+            expanded.args
+        );
+        assert_eq!(Some(json!({ "name": "e2e" })), expanded.expected);
+    }
+
+    #[test]
+    fn test_json_to_var() {
+        assert_eq!("abc", json_to_var(&json!("abc")));
+        assert_eq!("1", json_to_var(&json!(1)));
+        assert_eq!("[1,\"a\"]", json_to_var(&json!([1, "a"])));
+        assert_eq!("null", json_to_var(&Value::Null));
+    }
+}
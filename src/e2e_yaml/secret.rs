@@ -0,0 +1,335 @@
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::crypto;
+use super::template;
+use super::var::{Env, Vars};
+
+const MASK: &str = "***";
+
+/// Shortest value masked. Shorter ones would mask unrelated text, such as every
+/// digit for a secret `1`, so they are rejected when the secrets are loaded.
+pub const MIN_LENGTH: usize = 4;
+
+/// Environment variable holding the key of the encrypted secrets file by default.
+pub const KEY_ENV: &str = "E2E_SECRETS_KEY";
+
+#[derive(Debug, PartialEq)]
+pub struct SecretError {
+    pub kind: SecretErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SecretErrorKind {
+    /// Holds the secret and the environment variable.
+    EnvNotSet(String, String),
+    /// Holds the secret, the path and the reason.
+    FileReadFailed(String, String, String),
+    /// Holds the path and the reason.
+    InvalidEncryptedFile(String, String),
+    /// Holds the secret.
+    TooShort(String),
+}
+
+impl std::error::Error for SecretError {}
+
+impl std::fmt::Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SecretErrorKind::EnvNotSet(name, env) => {
+                writeln!(
+                    f,
+                    "secret '{}' not found. environment variable '{}' is not set",
+                    name, env
+                )
+            }
+            SecretErrorKind::FileReadFailed(name, path, reason) => {
+                writeln!(
+                    f,
+                    "secret '{}' not found. failed to read '{}'. {}",
+                    name, path, reason
+                )
+            }
+            SecretErrorKind::InvalidEncryptedFile(path, reason) => {
+                writeln!(
+                    f,
+                    "invalid encrypted secrets file '{}'. {}",
+                    path,
+                    reason.trim_end()
+                )
+            }
+            SecretErrorKind::TooShort(name) => {
+                writeln!(
+                    f,
+                    "secret '{}' is too short to be masked. use at least {} characters",
+                    name, MIN_LENGTH
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Secrets(pub IndexMap<String, Secret>);
+
+/// Where the value of a secret is read from. Only the source is part of the
+/// configuration, so printing it never reveals the value.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Secret {
+    Env(String),
+    /// Path relative to the configuration file. A trailing newline is removed.
+    File(String),
+}
+
+/// A YAML mapping of secrets encrypted with `e2e secrets encrypt`, decrypted in memory.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct EncryptedSecrets {
+    /// Path relative to the configuration file.
+    pub file: String,
+    /// Where the key is read from, `E2E_SECRETS_KEY` if omitted.
+    pub key: Option<Secret>,
+}
+
+impl Secrets {
+    /// Reads the values of the secrets. Files are resolved relative to `base_dir`.
+    pub fn load(&self, base_dir: &Path, env: Env) -> Result<Vars, SecretError> {
+        let mut values = Vars(IndexMap::new());
+        for (name, secret) in &self.0 {
+            values
+                .0
+                .insert(name.to_string(), secret.read(name, base_dir, env)?);
+        }
+        Ok(values)
+    }
+}
+
+impl Secret {
+    /// Reads the value of the secret `name`.
+    fn read(&self, name: &str, base_dir: &Path, env: Env) -> Result<String, SecretError> {
+        match self {
+            Secret::Env(var) => env(var).ok_or_else(|| SecretError {
+                kind: SecretErrorKind::EnvNotSet(name.to_string(), var.to_string()),
+            }),
+            Secret::File(path) => {
+                let path = base_dir.join(path);
+                let contents = std::fs::read_to_string(&path).map_err(|e| SecretError {
+                    kind: SecretErrorKind::FileReadFailed(
+                        name.to_string(),
+                        path.display().to_string(),
+                        e.to_string(),
+                    ),
+                })?;
+                Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+            }
+        }
+    }
+}
+
+impl EncryptedSecrets {
+    /// Decrypts the file and reads its secrets. Paths are resolved relative to `base_dir`.
+    pub fn load(&self, base_dir: &Path, env: Env) -> Result<Vars, SecretError> {
+        let key = match &self.key {
+            Some(key) => key.read("key", base_dir, env)?,
+            None => Secret::Env(KEY_ENV.to_string()).read("key", base_dir, env)?,
+        };
+        let path = base_dir.join(&self.file);
+        let invalid = |reason: String| SecretError {
+            kind: SecretErrorKind::InvalidEncryptedFile(path.display().to_string(), reason),
+        };
+        let contents = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+        let plaintext = crypto::decrypt(&contents, &key).map_err(|e| invalid(e.to_string()))?;
+        serde_yaml::from_str(&plaintext).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Fails on the first non-empty value shorter than `MIN_LENGTH`.
+pub fn check_lengths(values: &Vars) -> Result<(), SecretError> {
+    match values
+        .0
+        .iter()
+        .find(|(_, v)| !v.is_empty() && v.chars().count() < MIN_LENGTH)
+    {
+        Some((name, _)) => Err(SecretError {
+            kind: SecretErrorKind::TooShort(name.to_string()),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Replaces every occurrence of the values in `s` with `***`, including the values
+/// transformed by a filter or escaped by a template. Values shorter than `MIN_LENGTH`
+/// are left as is.
+pub fn mask(s: &str, values: &Vars) -> String {
+    let variants: Vec<String> = values
+        .0
+        .values()
+        .filter(|x| x.chars().count() >= MIN_LENGTH)
+        .flat_map(|x| variants(x))
+        .collect();
+    let mut values: Vec<&String> = variants.iter().collect();
+    // longer values first, so that a value containing another one is masked as a whole
+    values.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    values
+        .iter()
+        .fold(s.to_string(), |s, value| s.replace(value.as_str(), MASK))
+}
+
+/// Masks every string in `value`, so that the secrets are masked before the strings
+/// are quoted or escaped by serializing it.
+pub fn mask_yaml(value: serde_yaml::Value, values: &Vars) -> serde_yaml::Value {
+    use serde_yaml::Value;
+    match value {
+        Value::String(s) => Value::String(mask(&s, values)),
+        Value::Sequence(items) => {
+            Value::Sequence(items.into_iter().map(|x| mask_yaml(x, values)).collect())
+        }
+        Value::Mapping(entries) => Value::Mapping(
+            entries
+                .into_iter()
+                .map(|(k, v)| (mask_yaml(k, values), mask_yaml(v, values)))
+                .collect(),
+        ),
+        Value::Tagged(mut tagged) => {
+            tagged.value = mask_yaml(tagged.value, values);
+            Value::Tagged(tagged)
+        }
+        _ => value,
+    }
+}
+
+/// The forms of `value` found in the output: as is, filtered and with escaped braces.
+fn variants(value: &str) -> Vec<String> {
+    let json = serde_json::Value::String(value.to_string()).to_string();
+    vec![
+        value.to_string(),
+        value.to_uppercase(),
+        value.to_lowercase(),
+        template::urlencode(value),
+        json[1..json.len() - 1].to_string(),
+        template::escape(value),
+    ]
+}
+
+#[cfg(test)]
+mod secret_tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let env = |name: &str| (name == "E2E_SECRET_TEST_PASSWORD").then(|| "passw0rd".to_string());
+        let dir = std::env::temp_dir().join("e2e_secret_test_load");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("token.txt"), "t0ken\n").unwrap();
+
+        let yaml = "
+password: !env E2E_SECRET_TEST_PASSWORD
+token: !file token.txt
+";
+        let secrets: Secrets = serde_yaml::from_str(yaml).unwrap();
+        let values = secrets.load(&dir, &env).unwrap();
+        assert_eq!("passw0rd", values.0["password"]);
+        assert_eq!("t0ken", values.0["token"]);
+
+        let secrets: Secrets = serde_yaml::from_str("key: !env E2E_SECRET_TEST_UNSET").unwrap();
+        assert_eq!(
+            Some(SecretError {
+                kind: SecretErrorKind::EnvNotSet(
+                    "key".to_string(),
+                    "E2E_SECRET_TEST_UNSET".to_string()
+                )
+            }),
+            secrets.load(&dir, &env).err()
+        );
+        let secrets: Secrets = serde_yaml::from_str("key: !file missing.txt").unwrap();
+        assert!(secrets.load(&dir, &env).is_err());
+    }
+
+    #[test]
+    fn test_load_encrypted() {
+        let env = |_: &str| None;
+        let dir = std::env::temp_dir().join("e2e_secret_test_load_encrypted");
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = crypto::generate_key();
+        std::fs::write(dir.join("key.txt"), format!("{}\n", key)).unwrap();
+        let encrypted = crypto::encrypt("password: passw0rd\n", &key).unwrap();
+        std::fs::write(dir.join("secrets.enc"), encrypted).unwrap();
+
+        let yaml = "{ file: secrets.enc, key: !file key.txt }";
+        let secrets: EncryptedSecrets = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!("passw0rd", secrets.load(&dir, &env).unwrap().0["password"]);
+
+        let wrong_key = crypto::generate_key();
+        let env = |name: &str| (name == "E2E_SECRET_TEST_WRONG_KEY").then(|| wrong_key.clone());
+        let yaml = "{ file: secrets.enc, key: !env E2E_SECRET_TEST_WRONG_KEY }";
+        let secrets: EncryptedSecrets = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            secrets.load(&dir, &env).err().map(|x| x.kind),
+            Some(SecretErrorKind::InvalidEncryptedFile(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_mask() {
+        let values = Vars(IndexMap::from([
+            ("short".to_string(), "pass".to_string()),
+            ("long".to_string(), "passw0rd".to_string()),
+            ("empty".to_string(), String::new()),
+        ]));
+        assert_eq!(
+            "assert failed. expected '***', actual '***' ***",
+            mask(
+                "assert failed. expected 'passw0rd', actual 'pass' passw0rd",
+                &values
+            )
+        );
+        assert_eq!("nothing", mask("nothing", &values));
+
+        let values = Vars(IndexMap::from([(
+            "token".to_string(),
+            "a\"b c{d}".to_string(),
+        )]));
+        assert_eq!(
+            "*** *** *** *** ***",
+            mask(
+                "a\"b c{d} A\"B C{D} a%22b%20c%7Bd%7D a\\\"b c{d} a\"b c{{d}}",
+                &values
+            )
+        );
+    }
+
+    #[test]
+    fn test_short_values() {
+        let values = Vars(IndexMap::from([
+            ("pin".to_string(), "1".to_string()),
+            ("token".to_string(), "t0ken".to_string()),
+        ]));
+        assert_eq!("*** 1 2 10", mask("t0ken 1 2 10", &values));
+        assert_eq!(
+            Err(SecretError {
+                kind: SecretErrorKind::TooShort("pin".to_string())
+            }),
+            check_lengths(&values)
+        );
+        let values = Vars(IndexMap::from([
+            ("empty".to_string(), String::new()),
+            ("code".to_string(), "ab1c".to_string()),
+        ]));
+        assert_eq!(Ok(()), check_lengths(&values));
+    }
+
+    #[test]
+    fn test_mask_yaml() {
+        let values = Vars(IndexMap::from([(
+            "token".to_string(),
+            "se\"cr\\et".to_string(),
+        )]));
+        let value: serde_yaml::Value =
+            serde_yaml::from_str("{ a: [ !goto 'x se\"cr\\et' ], b: 1 }").unwrap();
+        let masked = serde_yaml::to_string(&mask_yaml(value, &values)).unwrap();
+        assert_eq!("a:\n- !goto x ***\nb: 1\n", masked);
+    }
+}
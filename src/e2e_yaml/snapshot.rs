@@ -0,0 +1,325 @@
+use std::fs;
+
+use serde::Deserialize;
+use serde::Serialize;
+use similar::TextDiff;
+use thirtyfour::{By, WebDriver};
+
+use super::context::RunContext;
+use super::step::{Step, StepError, StepErrorKind};
+
+#[derive(Debug, PartialEq)]
+pub struct SnapshotError {
+    pub kind: SnapshotErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotErrorKind {
+    /// A snapshot without name that may run more than once per scenario.
+    /// Holds the caller and the step location.
+    UnnamedInBlock(String, String),
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SnapshotErrorKind::UnnamedInBlock(caller, location) => writeln!(
+                f,
+                "snapshot without name at {} of {}. snapshots in blocks and tasks need a name",
+                location, caller
+            ),
+        }
+    }
+}
+
+/// Serializes the element as indented HTML with sorted attributes,
+/// one node per line and whitespace-only text nodes dropped.
+const HTML_SCRIPT: &str = r#"
+function serialize(node, depth, out) {
+    const indent = '  '.repeat(depth);
+    if (node.nodeType === Node.TEXT_NODE) {
+        const text = node.textContent.replace(/\s+/g, ' ').trim();
+        if (text) out.push(indent + text);
+        return;
+    }
+    if (node.nodeType !== Node.ELEMENT_NODE) return;
+    const tag = node.tagName.toLowerCase();
+    const attrs = Array.from(node.attributes)
+        .map(a => a.name + '="' + a.value + '"')
+        .sort();
+    out.push(indent + '<' + [tag].concat(attrs).join(' ') + '>');
+    node.childNodes.forEach(child => serialize(child, depth + 1, out));
+    out.push(indent + '</' + tag + '>');
+}
+const out = [];
+serialize(arguments[0], 0, out);
+return out.join('\n');
+"#;
+
+/// Builds an outline of the roles and accessible names of the element and
+/// its descendants, skipping hidden nodes and nodes without a role.
+const ARIA_SCRIPT: &str = r#"
+const implicit = {
+    a: 'link', article: 'article', aside: 'complementary', button: 'button',
+    dialog: 'dialog', footer: 'contentinfo', form: 'form', h1: 'heading',
+    h2: 'heading', h3: 'heading', h4: 'heading', h5: 'heading', h6: 'heading',
+    header: 'banner', img: 'img', li: 'listitem', main: 'main', nav: 'navigation',
+    ol: 'list', option: 'option', p: 'paragraph', section: 'region', select: 'combobox',
+    table: 'table', td: 'cell', textarea: 'textbox', th: 'columnheader', tr: 'row',
+    ul: 'list',
+};
+const inputRoles = {
+    button: 'button', checkbox: 'checkbox', radio: 'radio', range: 'slider',
+    search: 'searchbox', submit: 'button',
+};
+function role(el) {
+    if (el.hasAttribute('role')) return el.getAttribute('role');
+    const tag = el.tagName.toLowerCase();
+    if (tag === 'input') return inputRoles[el.type] || 'textbox';
+    return implicit[tag];
+}
+function name(el) {
+    if (el.hasAttribute('aria-label')) return el.getAttribute('aria-label');
+    if (el.hasAttribute('aria-labelledby')) {
+        return el.getAttribute('aria-labelledby').split(/\s+/)
+            .map(id => document.getElementById(id))
+            .filter(x => x)
+            .map(x => x.innerText)
+            .join(' ');
+    }
+    if (el.hasAttribute('alt')) return el.getAttribute('alt');
+    if (el.labels && el.labels.length) return el.labels[0].innerText;
+    if (el.hasAttribute('placeholder')) return el.getAttribute('placeholder');
+    if (el.hasAttribute('title')) return el.getAttribute('title');
+    return el.innerText || '';
+}
+function hidden(el) {
+    if (el.getAttribute('aria-hidden') === 'true') return true;
+    const style = window.getComputedStyle(el);
+    return style.display === 'none' || style.visibility === 'hidden';
+}
+function walk(el, depth, out) {
+    if (hidden(el)) return;
+    const r = role(el);
+    let next = depth;
+    if (r && r !== 'presentation' && r !== 'none') {
+        const n = name(el).replace(/\s+/g, ' ').trim();
+        out.push('  '.repeat(depth) + '- ' + r + (n ? ' "' + n + '"' : ''));
+        next = depth + 1;
+    }
+    Array.from(el.children).forEach(child => walk(child, next, out));
+}
+const out = [];
+walk(arguments[0], 0, out);
+return out.join('\n');
+"#;
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotMode {
+    Text,
+    Html,
+    Aria,
+}
+
+/// Compares the content of an element with `<snapshot_dir>/<name>.snap`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct SnapshotAssertion {
+    /// `<scenario>-<step index>` if omitted, only for the top-level steps of a scenario.
+    pub name: Option<String>,
+    pub selector: String,
+    pub mode: SnapshotMode,
+}
+
+impl SnapshotAssertion {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        SnapshotAssertion {
+            name: self.name.as_ref().map(|x| f(x)),
+            selector: f(&self.selector),
+            mode: self.mode.clone(),
+        }
+    }
+
+    fn name(&self, ctx: &RunContext) -> String {
+        match &self.name {
+            Some(name) => name.to_string(),
+            None => default_name(&ctx.builtins.scenario_id, ctx.builtins.step_index),
+        }
+    }
+
+    pub async fn run(&self, driver: &WebDriver, ctx: &RunContext) -> Result<(), StepError> {
+        let elem = driver.find(By::Css(&self.selector)).await?;
+        let raw = match self.mode {
+            SnapshotMode::Text => elem.text().await?,
+            SnapshotMode::Html => driver
+                .execute(HTML_SCRIPT, vec![elem.to_json()?])
+                .await?
+                .convert()?,
+            SnapshotMode::Aria => driver
+                .execute(ARIA_SCRIPT, vec![elem.to_json()?])
+                .await?
+                .convert()?,
+        };
+        let actual = normalize(&raw);
+
+        let name = self.name(ctx);
+        let path = ctx.snapshot_dir.join(format!("{}.snap", name));
+        if ctx.update_snapshots || !path.exists() {
+            println!("\twriting snapshot {}", path.display());
+            if let Some(dir) = path.parent() {
+                if !dir.exists() {
+                    fs::create_dir_all(dir)?;
+                }
+            }
+            fs::write(&path, actual)?;
+            return Ok(());
+        }
+
+        // normalized as well, so that a checkout with CRLF line endings still matches
+        let expected = normalize(&fs::read_to_string(&path)?);
+        if expected != actual {
+            return Err(StepError {
+                kind: StepErrorKind::SnapshotMismatch(name, unified_diff(&expected, &actual)),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the snapshots in `steps` have a name unless they are top-level steps
+/// of a scenario, as the default name is only unique for these. `caller` names the
+/// scenario or task the steps belong to.
+pub fn validate_names(caller: &str, steps: &[Step], in_task: bool) -> Result<(), SnapshotError> {
+    validate_names_recursive(caller, "steps", steps, !in_task)
+}
+
+fn validate_names_recursive(
+    caller: &str,
+    path: &str,
+    steps: &[Step],
+    top_level: bool,
+) -> Result<(), SnapshotError> {
+    for (i, step) in steps.iter().enumerate() {
+        let location = format!("{}[{}]", path, i);
+        if let Step::AssertSnapshot(SnapshotAssertion { name: None, .. }) = step {
+            if !top_level {
+                return Err(SnapshotError {
+                    kind: SnapshotErrorKind::UnnamedInBlock(caller.to_string(), location),
+                });
+            }
+        }
+        for (key, nested) in step.nested_steps() {
+            validate_names_recursive(caller, &format!("{}.{}", location, key), nested, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// `<scenario>-<step index>`, with the characters not allowed in file names replaced.
+fn default_name(scenario_id: &str, step_index: usize) -> String {
+    let scenario: String = scenario_id
+        .chars()
+        .map(|x| match x {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            x if x.is_whitespace() || x.is_control() => '_',
+            x => x,
+        })
+        .collect();
+    format!("{}-{}", scenario, step_index)
+}
+
+/// Strips trailing whitespace from every line, collapses runs of blank lines
+/// and terminates the content with a single newline.
+pub fn normalize(content: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in content.lines().map(|x| x.trim_end()) {
+        if line.is_empty() && lines.last().map_or(true, |x| x.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|x| x.is_empty()) {
+        lines.pop();
+    }
+    let mut result = lines.join("\n");
+    result.push('\n');
+    result
+}
+
+pub fn unified_diff(expected: &str, actual: &str) -> String {
+    TextDiff::from_lines(expected, actual)
+        .unified_diff()
+        .header("expected", "actual")
+        .to_string()
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let content = "\n\n  title  \n\n\n\nbody\t\n\n";
+        assert_eq!("  title\n\nbody\n", normalize(content));
+        assert_eq!("\n", normalize(""));
+        assert_eq!("title\n\nbody\n", normalize("title\r\n\r\nbody\r\n"));
+    }
+
+    #[test]
+    fn test_default_name() {
+        assert_eq!("checkout[ja]-2", default_name("checkout[ja]", 2));
+        assert_eq!("a_b_c-0", default_name("a/b c", 0));
+
+        let assertion: SnapshotAssertion =
+            serde_yaml::from_str("{ selector: '#terms', mode: text }").unwrap();
+        let mut ctx = RunContext::new(Default::default(), Default::default(), false);
+        ctx.builtins.scenario_id = "terms".to_string();
+        ctx.builtins.step_index = 3;
+        assert_eq!("terms-3", assertion.name(&ctx));
+    }
+
+    #[test]
+    fn test_validate_names() {
+        let steps: Vec<Step> = serde_yaml::from_str(
+            "
+- !assert_snapshot { selector: main, mode: text }
+- !repeat
+  times: 2
+  steps:
+    - !assert_snapshot { name: row, selector: tr, mode: text }
+    - !assert_snapshot { selector: td, mode: text }
+",
+        )
+        .unwrap();
+        assert_eq!(
+            Err(SnapshotError {
+                kind: SnapshotErrorKind::UnnamedInBlock(
+                    "scenario 's1'".to_string(),
+                    "steps[1].steps[1]".to_string()
+                )
+            }),
+            validate_names("scenario 's1'", &steps, false)
+        );
+        assert_eq!(Ok(()), validate_names("scenario 's1'", &steps[..1], false));
+        assert_eq!(
+            Err(SnapshotError {
+                kind: SnapshotErrorKind::UnnamedInBlock(
+                    "task 't1'".to_string(),
+                    "steps[0]".to_string()
+                )
+            }),
+            validate_names("task 't1'", &steps[..1], true)
+        );
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("a\nb\nc\n", "a\nx\nc\n");
+        assert_eq!(
+            "--- expected\n+++ actual\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c\n",
+            diff
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::Path;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde::Serialize;
+use thirtyfour::{Cookie, WebDriver};
+
+use super::step::{StepError, StepErrorKind};
+use super::storage::{self, StorageKind};
+
+/// Cookies and `localStorage` entries of the origins visited by a session.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BrowserState {
+    pub origins: Vec<OriginState>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OriginState {
+    pub origin: String,
+    pub cookies: Vec<Cookie>,
+    pub local_storage: IndexMap<String, String>,
+}
+
+/// Writes the state of the current origin to `path`.
+/// Origins already saved in the file are kept unless they are the current one.
+pub async fn save<P: AsRef<Path>>(driver: &WebDriver, path: P) -> Result<(), StepError> {
+    let p = path.as_ref();
+    let origin = driver.current_url().await?.origin().ascii_serialization();
+    let current = OriginState {
+        origin,
+        cookies: driver.get_all_cookies().await?,
+        local_storage: storage::get_all(driver, &StorageKind::Local).await?,
+    };
+
+    let mut state = if p.exists() {
+        read(p)?
+    } else {
+        BrowserState {
+            origins: Vec::new(),
+        }
+    };
+    state.origins.retain(|x| x.origin != current.origin);
+    state.origins.push(current);
+
+    if let Some(dir) = p.parent() {
+        if !dir.exists() {
+            fs::create_dir_all(dir)
+                .map_err(|e| state_file_failed("create the directory of", p, e))?;
+        }
+    }
+    let json = serde_json::to_string_pretty(&state).map_err(|e| invalid_state_file(p, e))?;
+    fs::write(p, json).map_err(|e| state_file_failed("write", p, e))?;
+    Ok(())
+}
+
+/// Seeds the session with the state saved in `path`.
+/// Every origin is visited once to restore its cookies and `localStorage`.
+pub async fn load<P: AsRef<Path>>(driver: &WebDriver, path: P) -> Result<(), StepError> {
+    let state = read(path.as_ref())?;
+    for origin in state.origins {
+        driver.goto(origin.origin.as_str()).await?;
+        driver.delete_all_cookies().await?;
+        for cookie in origin.cookies {
+            driver.add_cookie(cookie).await?;
+        }
+        storage::clear(driver, &StorageKind::Local).await?;
+        for (key, value) in &origin.local_storage {
+            storage::set_item(driver, &StorageKind::Local, key, value).await?;
+        }
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<BrowserState, StepError> {
+    let contents = fs::read_to_string(path).map_err(|e| state_file_failed("read", path, e))?;
+    serde_json::from_str(&contents).map_err(|e| invalid_state_file(path, e))
+}
+
+fn invalid_state_file(path: &Path, err: serde_json::Error) -> StepError {
+    StepError {
+        kind: StepErrorKind::InvalidStateFile(path.display().to_string(), err),
+    }
+}
+
+fn state_file_failed(operation: &str, path: &Path, err: std::io::Error) -> StepError {
+    StepError {
+        kind: StepErrorKind::StateFileFailed(
+            operation.to_string(),
+            path.display().to_string(),
+            err,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let json = r#"
+{
+  "origins": [
+    {
+      "origin": "https://example.com",
+      "cookies": [
+        { "name": "sid", "value": "abc", "path": "/", "secure": true }
+      ],
+      "local_storage": { "theme": "dark" }
+    }
+  ]
+}
+"#;
+        let state: BrowserState = serde_json::from_str(json).unwrap();
+        assert_eq!(1, state.origins.len());
+        let origin = &state.origins[0];
+        assert_eq!("https://example.com", origin.origin);
+        assert_eq!("sid", origin.cookies[0].name);
+        assert_eq!("abc", origin.cookies[0].value);
+        assert_eq!(Some(true), origin.cookies[0].secure);
+        assert_eq!(
+            IndexMap::from([("theme".to_string(), "dark".to_string())]),
+            origin.local_storage
+        );
+    }
+
+    #[test]
+    fn test_read_missing() {
+        let path = std::env::temp_dir().join("e2e_state_test_missing/user.json");
+        let err = read(&path).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("failed to read state file '{}'. ", path.display())));
+
+        let path = std::env::temp_dir().join("e2e_state_test_invalid.json");
+        fs::write(&path, "{}").unwrap();
+        let err = read(&path).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("invalid state file '{}'. ", path.display())));
+    }
+}
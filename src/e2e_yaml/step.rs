@@ -1,17 +1,26 @@
-use std::fs;
-use std::path::Path;
-use std::time::Duration;
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
 
 use crate::e2e_yaml::Vars;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
 use serde::Deserialize;
 use serde::Serialize;
-use thirtyfour::error::WebDriverError;
+use thirtyfour::error::{WebDriverError, WebDriverErrorInner};
 use thirtyfour::extensions::query::*;
-use thirtyfour::By;
+use thirtyfour::{By, Cookie};
 
+use super::builtin::Builtins;
+use super::condition::Condition;
+use super::context::RunContext;
+use super::screenshot::{ScreenShot, ScreenShotAssertion};
+use super::script::{json_to_var, Script};
+use super::snapshot::SnapshotAssertion;
+use super::state;
+use super::storage::{self, StorageKind};
+use super::task::{TaskArgs, Tasks};
+use super::template;
+use super::var::VarError;
 use super::E2eYaml;
-use crate::e2e_yaml::var::parse_var_names;
 
 pub struct StepError {
     pub kind: StepErrorKind,
@@ -22,6 +31,23 @@ pub enum StepErrorKind {
     DirectoryCreateFailed(std::io::Error),
     AssertFailed(String, String),
     TaskNotFound(String),
+    InvalidTaskCall(String, String),
+    OutputNotSet(String, String),
+    VarError(VarError),
+    AlertNotDisplayed(u64),
+    UnexpectedAlertOpen(String, String),
+    ScriptFailed(String, String),
+    /// Holds the path and the reason.
+    InvalidStateFile(String, serde_json::Error),
+    /// Holds the operation, the path and the reason.
+    StateFileFailed(String, String, std::io::Error),
+    ImageError(image::ImageError),
+    /// Holds the name, the ratio of differing pixels, the threshold and the images written.
+    ScreenshotMismatch(String, f64, f64, Vec<String>),
+    SnapshotMismatch(String, String),
+    InvalidItems(String),
+    MaxIterationsExceeded(u64),
+    RetryExhausted(Vec<StepError>),
 }
 
 impl From<WebDriverError> for StepError {
@@ -40,6 +66,22 @@ impl From<std::io::Error> for StepError {
     }
 }
 
+impl From<VarError> for StepError {
+    fn from(err: VarError) -> Self {
+        Self {
+            kind: StepErrorKind::VarError(err),
+        }
+    }
+}
+
+impl From<image::ImageError> for StepError {
+    fn from(err: image::ImageError) -> Self {
+        Self {
+            kind: StepErrorKind::ImageError(err),
+        }
+    }
+}
+
 impl std::fmt::Display for StepError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.kind {
@@ -55,6 +97,73 @@ impl std::fmt::Display for StepError {
             StepErrorKind::TaskNotFound(id) => {
                 writeln!(f, "task with id '{}' not found in configuration", id)
             }
+            StepErrorKind::InvalidTaskCall(id, reason) => {
+                writeln!(f, "\tinvalid call of task '{}'. {}", id, reason)
+            }
+            StepErrorKind::OutputNotSet(id, output) => {
+                writeln!(f, "\ttask '{}' did not set its output '{}'", id, output)
+            }
+            StepErrorKind::VarError(e) => write!(f, "{}", e),
+            StepErrorKind::AlertNotDisplayed(timeout) => {
+                writeln!(f, "\talert was not displayed within {}ms", timeout)
+            }
+            StepErrorKind::UnexpectedAlertOpen(step, text) => {
+                writeln!(
+                    f,
+                    "\tunexpected alert '{}' was open while running {}",
+                    text, step
+                )
+            }
+            StepErrorKind::ScriptFailed(message, stacktrace) => {
+                writeln!(f, "\tscript failed. {}", message)?;
+                for line in stacktrace.lines() {
+                    writeln!(f, "\t\t{}", line)?;
+                }
+                Ok(())
+            }
+            StepErrorKind::InvalidStateFile(path, e) => {
+                writeln!(f, "invalid state file '{}'. {}", path, e)
+            }
+            StepErrorKind::StateFileFailed(operation, path, e) => {
+                writeln!(f, "failed to {} state file '{}'. {}", operation, path, e)
+            }
+            StepErrorKind::ImageError(e) => writeln!(f, "{}", e),
+            StepErrorKind::ScreenshotMismatch(name, ratio, threshold, artifacts) => {
+                writeln!(
+                    f,
+                    "\tscreenshot '{}' differs from baseline by {:.2}% (threshold {:.2}%)",
+                    name,
+                    ratio * 100.0,
+                    threshold * 100.0
+                )?;
+                for artifact in artifacts {
+                    writeln!(f, "\t\twrote {}", artifact)?;
+                }
+                Ok(())
+            }
+            StepErrorKind::InvalidItems(items) => {
+                writeln!(f, "\titems must be a list or a JSON array. got '{}'", items)
+            }
+            StepErrorKind::MaxIterationsExceeded(max) => {
+                writeln!(f, "\tcondition still holds after {} iterations", max)
+            }
+            StepErrorKind::RetryExhausted(errors) => {
+                writeln!(f, "\tfailed after {} attempts", errors.len())?;
+                for (i, e) in errors.iter().enumerate() {
+                    writeln!(f, "\tattempt {}:", i + 1)?;
+                    for line in e.to_string().lines() {
+                        writeln!(f, "\t{}", line)?;
+                    }
+                }
+                Ok(())
+            }
+            StepErrorKind::SnapshotMismatch(name, diff) => {
+                writeln!(f, "\tsnapshot '{}' differs", name)?;
+                for line in diff.lines() {
+                    writeln!(f, "\t{}", line)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -69,22 +178,99 @@ pub enum Step {
         selector: String,
         value: String,
     },
-    ScreenShot(String),
+    ScreenShot(ScreenShot),
     WaitDisplayed {
         selector: String,
         timeout: u64,
         interval: u64,
     },
     AcceptAlert,
+    DismissAlert,
+    AcceptPrompt(String),
+    WaitAlert {
+        timeout: u64,
+        interval: u64,
+    },
     TaskRun {
         id: String,
-        args: Option<Vec<String>>,
+        args: Option<TaskArgs>,
+        /// Stores the only output of the task under this name.
+        #[serde(rename = "as")]
+        store_as: Option<String>,
+        /// Maps outputs of the task to the variables they are stored in.
+        outputs: Option<IndexMap<String, String>>,
     },
     AssertEq {
         kind: ValueKind,
         expected: String,
         selector: String,
     },
+    ExecuteScript(Script),
+    ExecuteAsyncScript(Script),
+    SetCookie {
+        name: String,
+        value: String,
+        path: Option<String>,
+        domain: Option<String>,
+        secure: Option<bool>,
+        expiry: Option<i64>,
+    },
+    GetCookie {
+        name: String,
+        #[serde(rename = "as")]
+        store_as: String,
+    },
+    DeleteCookie(String),
+    DeleteAllCookies,
+    SetStorage {
+        kind: StorageKind,
+        key: String,
+        value: String,
+    },
+    GetStorage {
+        kind: StorageKind,
+        key: String,
+        #[serde(rename = "as")]
+        store_as: String,
+    },
+    RemoveStorage {
+        kind: StorageKind,
+        key: String,
+    },
+    ClearStorage(StorageKind),
+    SaveState {
+        path: String,
+    },
+    AssertScreenshot(ScreenShotAssertion),
+    AssertSnapshot(SnapshotAssertion),
+    If {
+        condition: Condition,
+        then: Vec<Step>,
+        #[serde(rename = "else")]
+        else_steps: Option<Vec<Step>>,
+    },
+    Repeat {
+        times: u64,
+        #[serde(rename = "as")]
+        store_as: Option<String>,
+        steps: Vec<Step>,
+    },
+    ForEach {
+        items: Items,
+        #[serde(rename = "as")]
+        store_as: String,
+        steps: Vec<Step>,
+    },
+    While {
+        condition: Condition,
+        max_iterations: u64,
+        steps: Vec<Step>,
+    },
+    Retry {
+        attempts: NonZeroU64,
+        backoff_ms: Option<u64>,
+        steps: Vec<Step>,
+    },
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -95,108 +281,265 @@ pub enum ValueKind {
     Class,
 }
 
+/// Items of `!for_each`, either a list or a JSON array held by a variable.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Items {
+    List(Vec<String>),
+    Var(String),
+}
+
+impl Items {
+    fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        match self {
+            Items::List(items) => Items::List(items.iter().map(|x| f(x)).collect()),
+            Items::Var(items) => Items::Var(f(items)),
+        }
+    }
+
+    fn resolve(&self) -> Result<Vec<String>, StepError> {
+        match self {
+            Items::List(items) => Ok(items.clone()),
+            Items::Var(items) => match serde_json::from_str::<Vec<serde_json::Value>>(items) {
+                Ok(values) => Ok(values.iter().map(json_to_var).collect()),
+                Err(_) => Err(StepError {
+                    kind: StepErrorKind::InvalidItems(items.to_string()),
+                }),
+            },
+        }
+    }
+}
+
 impl Step {
-    pub fn expand_var(&self, name: &str, value: &str) -> Self {
-        let key = format!("{{{}}}", name);
-        let k = key.as_str();
+    /// Applies `f` to every string of the step that may hold variables,
+    /// including those of nested steps.
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: &F) -> Self {
         match self {
-            Step::Goto(url) => Step::Goto(url.replace(k, value)),
-            Step::Click(selector) => Step::Click(selector.replace(k, value)),
-            Step::Focus(selector) => Step::Focus(selector.replace(k, value)),
-            Step::SendKeys {
-                selector,
-                value: val,
-            } => Step::SendKeys {
-                selector: selector.replace(k, value),
-                value: val.replace(k, value),
+            Step::Goto(url) => Step::Goto(f(url)),
+            Step::Click(selector) => Step::Click(f(selector)),
+            Step::Focus(selector) => Step::Focus(f(selector)),
+            Step::SendKeys { selector, value } => Step::SendKeys {
+                selector: f(selector),
+                value: f(value),
             },
-            Step::ScreenShot(path) => Step::ScreenShot(path.replace(k, value)),
+            Step::ScreenShot(shot) => Step::ScreenShot(shot.map_strings(f)),
             Step::WaitDisplayed {
                 selector,
                 timeout,
                 interval,
             } => Step::WaitDisplayed {
-                selector: selector.replace(k, value),
+                selector: f(selector),
                 timeout: *timeout,
                 interval: *interval,
             },
             Step::AcceptAlert => Step::AcceptAlert,
-            Step::TaskRun { id, args } => {
-                if let Some(args) = args {
-                    let args: Vec<String> = args.iter().map(|arg| arg.replace(k, value)).collect();
-                    Step::TaskRun {
-                        id: id.clone(),
-                        args: Some(args),
-                    }
-                } else {
-                    Step::TaskRun {
-                        id: id.clone(),
-                        args: None,
-                    }
-                }
-            }
+            Step::DismissAlert => Step::DismissAlert,
+            Step::AcceptPrompt(text) => Step::AcceptPrompt(f(text)),
+            Step::WaitAlert { timeout, interval } => Step::WaitAlert {
+                timeout: *timeout,
+                interval: *interval,
+            },
+            Step::TaskRun {
+                id,
+                args,
+                store_as,
+                outputs,
+            } => Step::TaskRun {
+                id: id.clone(),
+                args: args.as_ref().map(|x| x.map_strings(|y| f(y))),
+                store_as: store_as.clone(),
+                outputs: outputs.clone(),
+            },
             Step::AssertEq {
                 kind,
                 expected,
                 selector,
             } => Step::AssertEq {
                 kind: kind.clone(),
-                expected: expected.replace(k, value),
-                selector: selector.replace(k, value),
+                expected: f(expected),
+                selector: f(selector),
+            },
+            Step::ExecuteScript(script) => Step::ExecuteScript(script.map_strings(f)),
+            Step::ExecuteAsyncScript(script) => Step::ExecuteAsyncScript(script.map_strings(f)),
+            Step::SetCookie {
+                name,
+                value: val,
+                path,
+                domain,
+                secure,
+                expiry,
+            } => Step::SetCookie {
+                name: f(name),
+                value: f(val),
+                path: path.as_ref().map(|x| f(x)),
+                domain: domain.as_ref().map(|x| f(x)),
+                secure: *secure,
+                expiry: *expiry,
+            },
+            Step::GetCookie { name, store_as } => Step::GetCookie {
+                name: f(name),
+                store_as: store_as.clone(),
+            },
+            Step::DeleteCookie(name) => Step::DeleteCookie(f(name)),
+            Step::DeleteAllCookies => Step::DeleteAllCookies,
+            Step::SetStorage {
+                kind,
+                key,
+                value: val,
+            } => Step::SetStorage {
+                kind: kind.clone(),
+                key: f(key),
+                value: f(val),
+            },
+            Step::GetStorage {
+                kind,
+                key,
+                store_as,
+            } => Step::GetStorage {
+                kind: kind.clone(),
+                key: f(key),
+                store_as: store_as.clone(),
+            },
+            Step::RemoveStorage { kind, key } => Step::RemoveStorage {
+                kind: kind.clone(),
+                key: f(key),
+            },
+            Step::ClearStorage(kind) => Step::ClearStorage(kind.clone()),
+            Step::SaveState { path } => Step::SaveState { path: f(path) },
+            Step::AssertScreenshot(assertion) => Step::AssertScreenshot(assertion.map_strings(f)),
+            Step::AssertSnapshot(assertion) => Step::AssertSnapshot(assertion.map_strings(f)),
+            Step::If {
+                condition,
+                then,
+                else_steps,
+            } => Step::If {
+                condition: condition.map_strings(f),
+                then: then.iter().map(|x| x.map_strings(f)).collect(),
+                else_steps: else_steps
+                    .as_ref()
+                    .map(|x| x.iter().map(|y| y.map_strings(f)).collect()),
+            },
+            Step::Repeat {
+                times,
+                store_as,
+                steps,
+            } => Step::Repeat {
+                times: *times,
+                store_as: store_as.clone(),
+                steps: steps.iter().map(|x| x.map_strings(f)).collect(),
+            },
+            Step::ForEach {
+                items,
+                store_as,
+                steps,
+            } => Step::ForEach {
+                items: items.map_strings(f),
+                store_as: store_as.clone(),
+                steps: steps.iter().map(|x| x.map_strings(f)).collect(),
+            },
+            Step::While {
+                condition,
+                max_iterations,
+                steps,
+            } => Step::While {
+                condition: condition.map_strings(f),
+                max_iterations: *max_iterations,
+                steps: steps.iter().map(|x| x.map_strings(f)).collect(),
+            },
+            Step::Retry {
+                attempts,
+                backoff_ms,
+                steps,
+            } => Step::Retry {
+                attempts: *attempts,
+                backoff_ms: *backoff_ms,
+                steps: steps.iter().map(|x| x.map_strings(f)).collect(),
             },
         }
     }
 
+    /// Expands `vars`, except for the variable of a loop in the steps of the loop,
+    /// which it shadows.
     pub fn expand_vars(&self, vars: &Vars) -> Self {
+        let mut expanded = self.map_strings(&|x| expand(x, vars));
+        let loop_var = match self {
+            Step::Repeat {
+                store_as: Some(name),
+                ..
+            }
+            | Step::ForEach { store_as: name, .. } => Some(name),
+            _ => None,
+        };
+        let mut inner = vars.clone();
+        if let Some(name) = loop_var {
+            inner.0.shift_remove(name);
+        }
+        let nested = self.nested_steps();
+        for ((_, steps), expanded_steps) in nested.iter().zip(expanded.nested_steps_mut()) {
+            *expanded_steps = steps.iter().map(|x| x.expand_vars(&inner)).collect();
+        }
+        expanded
+    }
+
+    /// Expands `vars` and falls back to the defaults of the variables still missing.
+    fn expand_final(&self, vars: &Vars, builtins: &Builtins) -> Self {
+        self.map_strings(&|x| expand_with_defaults(x, vars, builtins))
+    }
+
+    /// Runs the step after expanding the variables stored by previous steps.
+    pub async fn run(
+        &self,
+        driver: &thirtyfour::WebDriver,
+        config: &E2eYaml,
+        ctx: &mut RunContext,
+    ) -> Result<(), StepError> {
+        let step = self.expand_runtime_vars(&ctx.vars, &ctx.builtins);
+        step.run_step(driver, config, ctx).await.map_err(|err| {
+            let text = match &err.kind {
+                StepErrorKind::WebDriverError(e) => match e.as_inner() {
+                    WebDriverErrorInner::UnexpectedAlertOpen(info) => info
+                        .value
+                        .data
+                        .as_ref()
+                        .and_then(|x| x.get("text"))
+                        .and_then(|x| x.as_str())
+                        .unwrap_or(info.value.message.as_str())
+                        .to_string(),
+                    _ => return err,
+                },
+                _ => return err,
+            };
+            StepError {
+                kind: StepErrorKind::UnexpectedAlertOpen(step.describe(), text),
+            }
+        })
+    }
+
+    /// Blocks are expanded when they run their nested steps, so that those see
+    /// the variables stored by the steps before them.
+    fn expand_runtime_vars(&self, vars: &Vars, builtins: &Builtins) -> Self {
         match self {
-            Step::Goto(url) => Step::Goto(expand(url, vars)),
-            Step::Click(selector) => Step::Click(expand(selector, vars)),
-            Step::Focus(selector) => Step::Focus(expand(selector, vars)),
-            Step::SendKeys { selector, value } => Step::SendKeys {
-                selector: expand(selector, vars),
-                value: expand(value, vars),
-            },
-            Step::ScreenShot(path) => Step::ScreenShot(expand(path, vars)),
-            Step::WaitDisplayed {
-                selector,
-                timeout,
-                interval,
-            } => Step::WaitDisplayed {
-                selector: expand(selector, vars),
-                timeout: *timeout,
-                interval: *interval,
-            },
-            Step::AcceptAlert => Step::AcceptAlert,
-            Step::TaskRun { id, args } => {
-                if let Some(args) = args {
-                    let expanded: Vec<String> = args.iter().map(|x| expand(x, vars)).collect();
-                    Step::TaskRun {
-                        id: id.clone(),
-                        args: Some(expanded),
-                    }
-                } else {
-                    Step::TaskRun {
-                        id: id.clone(),
-                        args: None,
-                    }
-                }
+            Step::If { .. } | Step::Repeat { .. } | Step::While { .. } | Step::Retry { .. } => {
+                self.clone()
             }
-            Step::AssertEq {
-                kind,
-                expected,
-                selector,
-            } => Step::AssertEq {
-                kind: kind.clone(),
-                expected: expand(expected, vars),
-                selector: expand(selector, vars),
+            Step::ForEach {
+                items,
+                store_as,
+                steps,
+            } => Step::ForEach {
+                items: items.map_strings(|x| expand_with_defaults(x, vars, builtins)),
+                store_as: store_as.clone(),
+                steps: steps.clone(),
             },
+            _ => self.expand_final(vars, builtins),
         }
     }
 
-    pub async fn run(
+    async fn run_step(
         &self,
         driver: &thirtyfour::WebDriver,
         config: &E2eYaml,
+        ctx: &mut RunContext,
     ) -> Result<(), StepError> {
         match self {
             Step::Goto(url) => driver.goto(url).await?,
@@ -213,15 +556,7 @@ impl Step {
                 elem.clear().await?;
                 elem.send_keys(value).await?;
             }
-            Step::ScreenShot(file_name) => {
-                let p = Path::new(file_name);
-                if let Some(dir) = p.parent() {
-                    if !dir.exists() {
-                        fs::create_dir_all(dir)?;
-                    }
-                }
-                driver.screenshot(Path::new(file_name)).await?
-            }
+            Step::ScreenShot(shot) => shot.run(driver).await?,
             Step::WaitDisplayed {
                 selector,
                 timeout,
@@ -242,7 +577,37 @@ impl Step {
             Step::AcceptAlert => {
                 driver.accept_alert().await?;
             }
-            Step::TaskRun { id, args } => {
+            Step::DismissAlert => {
+                driver.dismiss_alert().await?;
+            }
+            Step::AcceptPrompt(text) => {
+                driver.send_alert_text(text.as_str()).await?;
+                driver.accept_alert().await?;
+            }
+            Step::WaitAlert { timeout, interval } => {
+                let start = Instant::now();
+                loop {
+                    match driver.get_alert_text().await {
+                        Ok(_) => break,
+                        Err(e) => match e.as_inner() {
+                            WebDriverErrorInner::NoSuchAlert(_) => {}
+                            _ => return Err(e.into()),
+                        },
+                    }
+                    if start.elapsed() >= Duration::from_millis(*timeout) {
+                        return Err(StepError {
+                            kind: StepErrorKind::AlertNotDisplayed(*timeout),
+                        });
+                    }
+                    tokio::time::sleep(Duration::from_millis(*interval)).await;
+                }
+            }
+            Step::TaskRun {
+                id,
+                args,
+                store_as,
+                outputs,
+            } => {
                 let tasks = match &config.tasks {
                     Some(tasks) => tasks,
                     None => {
@@ -259,21 +624,48 @@ impl Step {
                         })
                     }
                 };
-                let default_vars = Vars(IndexMap::new());
-                let default_vars = match &config.vars {
-                    Some(vars) => vars,
-                    None => &default_vars,
+                // args are bound first, then the task vars, the variables stored by
+                // the task, the variables of the running scenario and the global ones,
+                // in this order of precedence
+                let mut vars = config.global_vars().merged(&ctx.vars);
+                let invalid_call = |reason: String| StepError {
+                    kind: StepErrorKind::InvalidTaskCall(id.to_string(), reason),
                 };
-                let args: Option<Vec<&str>> = args
-                    .as_ref()
-                    .map(|x| x.iter().map(|y| y.as_str()).collect());
-                let steps = t.expand_args(args.as_deref());
-                let steps: Vec<Step> = steps
-                    .into_iter()
-                    .map(|x| x.expand_vars(default_vars))
-                    .collect();
-                for ele in steps {
-                    Box::pin(ele.run(driver, config)).await?;
+                let mut stored = IndexSet::new();
+                Step::collect_stored_vars(&t.steps, Some(tasks), &mut stored);
+                let outer = vars.without(&stored);
+                if let Some(task_vars) = &t.vars {
+                    // task vars may use the args, so they are resolved per call
+                    let bound = Vars(t.bind_args(args.as_ref()).map_err(invalid_call)?);
+                    let task_vars = task_vars
+                        .resolve(&vars.merged(&bound))?
+                        .bind(&ctx.builtins)?;
+                    vars = outer.merged(&task_vars);
+                } else {
+                    vars = outer;
+                }
+                let steps = t.expand_args(args.as_ref()).map_err(invalid_call)?;
+                let outputs = t
+                    .bind_outputs(store_as.as_deref(), outputs.as_ref())
+                    .map_err(invalid_call)?;
+                let steps: Vec<Step> = steps.into_iter().map(|x| x.expand_vars(&vars)).collect();
+
+                // variables set by the task are local to it, except for its outputs
+                let caller_vars = ctx.vars.clone();
+                let result = Step::run_steps(&steps, driver, config, ctx).await;
+                let task_vars = std::mem::replace(&mut ctx.vars, caller_vars);
+                result?;
+                for (output, var) in outputs {
+                    match task_vars.0.get(&output) {
+                        Some(value) => {
+                            ctx.vars.0.insert(var, value.to_string());
+                        }
+                        None => {
+                            return Err(StepError {
+                                kind: StepErrorKind::OutputNotSet(id.to_string(), output),
+                            })
+                        }
+                    }
                 }
             }
             Step::AssertEq {
@@ -293,21 +685,261 @@ impl Step {
                     });
                 }
             }
+            Step::ExecuteScript(script) => script.execute(driver, &mut ctx.vars, false).await?,
+            Step::ExecuteAsyncScript(script) => script.execute(driver, &mut ctx.vars, true).await?,
+            Step::SetCookie {
+                name,
+                value,
+                path,
+                domain,
+                secure,
+                expiry,
+            } => {
+                let mut cookie = Cookie::new(name, value);
+                cookie.path = path.clone();
+                cookie.domain = domain.clone();
+                cookie.secure = *secure;
+                cookie.expiry = *expiry;
+                driver.add_cookie(cookie).await?;
+            }
+            Step::GetCookie { name, store_as } => {
+                let cookie = driver.get_named_cookie(name).await?;
+                ctx.vars.0.insert(store_as.to_string(), cookie.value);
+            }
+            Step::DeleteCookie(name) => driver.delete_cookie(name).await?,
+            Step::DeleteAllCookies => driver.delete_all_cookies().await?,
+            Step::SetStorage { kind, key, value } => {
+                storage::set_item(driver, kind, key, value).await?;
+            }
+            Step::GetStorage {
+                kind,
+                key,
+                store_as,
+            } => {
+                let value = storage::get_item(driver, kind, key).await?;
+                ctx.vars
+                    .0
+                    .insert(store_as.to_string(), value.unwrap_or("".to_string()));
+            }
+            Step::RemoveStorage { kind, key } => storage::remove_item(driver, kind, key).await?,
+            Step::ClearStorage(kind) => storage::clear(driver, kind).await?,
+            Step::SaveState { path } => state::save(driver, path).await?,
+            Step::AssertScreenshot(assertion) => assertion.run(driver, ctx).await?,
+            Step::AssertSnapshot(assertion) => assertion.run(driver, ctx).await?,
+            Step::If {
+                condition,
+                then,
+                else_steps,
+            } => {
+                let steps = if condition
+                    .expand_vars(&ctx.vars, &ctx.builtins)
+                    .evaluate(driver)
+                    .await?
+                {
+                    then
+                } else {
+                    match else_steps {
+                        Some(steps) => steps,
+                        None => return Ok(()),
+                    }
+                };
+                Step::run_steps(steps, driver, config, ctx).await?;
+            }
+            Step::Repeat {
+                times,
+                store_as,
+                steps,
+            } => {
+                for i in 0..*times {
+                    if let Some(name) = store_as {
+                        ctx.vars.0.insert(name.to_string(), i.to_string());
+                    }
+                    Step::run_steps(steps, driver, config, ctx).await?;
+                }
+            }
+            Step::ForEach {
+                items,
+                store_as,
+                steps,
+            } => {
+                for item in items.resolve()? {
+                    ctx.vars.0.insert(store_as.to_string(), item);
+                    Step::run_steps(steps, driver, config, ctx).await?;
+                }
+            }
+            Step::While {
+                condition,
+                max_iterations,
+                steps,
+            } => {
+                let mut iterations = 0;
+                while condition
+                    .expand_vars(&ctx.vars, &ctx.builtins)
+                    .evaluate(driver)
+                    .await?
+                {
+                    if iterations == *max_iterations {
+                        return Err(StepError {
+                            kind: StepErrorKind::MaxIterationsExceeded(*max_iterations),
+                        });
+                    }
+                    Step::run_steps(steps, driver, config, ctx).await?;
+                    iterations += 1;
+                }
+            }
+            Step::Retry {
+                attempts,
+                backoff_ms,
+                steps,
+            } => {
+                let attempts = attempts.get();
+                let mut errors: Vec<StepError> = Vec::new();
+                for attempt in 1..=attempts {
+                    match Step::run_steps(steps, driver, config, ctx).await {
+                        Ok(()) => break,
+                        Err(err) => errors.push(err),
+                    }
+                    if attempt < attempts {
+                        let backoff = backoff_ms.unwrap_or(0).saturating_mul(attempt);
+                        tokio::time::sleep(Duration::from_millis(backoff)).await;
+                    }
+                }
+                ctx.record_retry(errors.len() as u64, attempts);
+                if errors.len() as u64 == attempts {
+                    return Err(StepError {
+                        kind: StepErrorKind::RetryExhausted(errors),
+                    });
+                }
+            }
         }
         Ok(())
     }
-}
 
-fn expand(orig: &str, vars: &Vars) -> String {
-    let mut result = orig.to_string();
-    if let Some(names) = parse_var_names(orig) {
-        for name in names {
-            let key = format!("{{{}}}", name);
-            let value = vars.0.get(name.as_str()).unwrap_or(&key);
-            result = result.replace(key.as_str(), value);
+    async fn run_steps(
+        steps: &[Step],
+        driver: &thirtyfour::WebDriver,
+        config: &E2eYaml,
+        ctx: &mut RunContext,
+    ) -> Result<(), StepError> {
+        for step in steps {
+            Box::pin(step.run(driver, config, ctx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Variables stored at run time by the steps, including the outputs of the tasks they run.
+    pub fn collect_stored_vars(steps: &[Step], tasks: Option<&Tasks>, out: &mut IndexSet<String>) {
+        for step in steps {
+            match step {
+                Step::ExecuteScript(script) | Step::ExecuteAsyncScript(script) => {
+                    if let Some(store_as) = &script.store_as {
+                        out.insert(store_as.to_string());
+                    }
+                }
+                Step::GetCookie { store_as, .. }
+                | Step::GetStorage { store_as, .. }
+                | Step::ForEach { store_as, .. } => {
+                    out.insert(store_as.to_string());
+                }
+                Step::Repeat {
+                    store_as: Some(store_as),
+                    ..
+                } => {
+                    out.insert(store_as.to_string());
+                }
+                Step::TaskRun {
+                    id,
+                    store_as,
+                    outputs,
+                    ..
+                } => {
+                    let task = tasks.and_then(|x| x.0.get(id));
+                    if let Some(Ok(bound)) =
+                        task.map(|x| x.bind_outputs(store_as.as_deref(), outputs.as_ref()))
+                    {
+                        out.extend(bound.into_values());
+                    }
+                }
+                _ => {}
+            }
+            for (_, nested) in step.nested_steps() {
+                Step::collect_stored_vars(nested, tasks, out);
+            }
+        }
+    }
+
+    fn nested_steps_mut(&mut self) -> Vec<&mut Vec<Step>> {
+        match self {
+            Step::If {
+                then, else_steps, ..
+            } => {
+                let mut nested = vec![then];
+                if let Some(else_steps) = else_steps {
+                    nested.push(else_steps);
+                }
+                nested
+            }
+            Step::Repeat { steps, .. }
+            | Step::ForEach { steps, .. }
+            | Step::While { steps, .. }
+            | Step::Retry { steps, .. } => vec![steps],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The kind of the step and the element it targets. Values such as the keys
+    /// sent are left out, since they may hold secrets.
+    fn describe(&self) -> String {
+        let kind = match serde_yaml::to_value(self) {
+            Ok(serde_yaml::Value::Tagged(tagged)) => tagged.tag.to_string(),
+            Ok(serde_yaml::Value::String(kind)) => format!("!{}", kind),
+            _ => "step".to_string(),
+        };
+        let selector = match self {
+            Step::Click(selector) | Step::Focus(selector) => Some(selector),
+            Step::SendKeys { selector, .. }
+            | Step::WaitDisplayed { selector, .. }
+            | Step::AssertEq { selector, .. } => Some(selector),
+            _ => None,
+        };
+        match selector {
+            Some(selector) => format!("{} '{}'", kind, selector),
+            None => kind,
+        }
+    }
+
+    /// Steps nested in blocks such as `!if` and loops, keyed by their field name.
+    pub fn nested_steps(&self) -> Vec<(&'static str, &[Step])> {
+        match self {
+            Step::If {
+                then, else_steps, ..
+            } => {
+                let mut nested = vec![("then", then.as_slice())];
+                if let Some(else_steps) = else_steps {
+                    nested.push(("else", else_steps));
+                }
+                nested
+            }
+            Step::Repeat { steps, .. }
+            | Step::ForEach { steps, .. }
+            | Step::While { steps, .. }
+            | Step::Retry { steps, .. } => vec![("steps", steps)],
+            _ => Vec::new(),
         }
-    };
-    result
+    }
+}
+
+/// Replaces the variables found in `vars`. `{env:NAME}` and `${NAME}` read the
+/// environment instead. Missing variables are left as is, defaults included,
+/// since a later expansion may still provide them. See [`template::render`].
+pub fn expand(orig: &str, vars: &Vars) -> String {
+    template::render(orig, vars)
+}
+
+/// Renders the template for the last time, evaluating the built-ins, replacing a missing
+/// `{name:-fallback}` with `fallback` and the escaped braces with literal ones.
+pub fn expand_with_defaults(orig: &str, vars: &Vars, builtins: &Builtins) -> String {
+    template::render_final(orig, vars, builtins)
 }
 
 #[cfg(test)]
@@ -327,6 +959,9 @@ mod step_tests {
  - !wait_displayed { selector: '{app}', timeout: 3000, interval: 1000 }
  - !task_run { id: login, args: [ 'admin', '{app}' ] }
  - !assert_eq { kind: text, expected: '{app}', selector: '{app}' }
+ - !accept_prompt '{app}'
+ - !set_cookie { name: '{app}', value: '{app}', domain: '{app}' }
+ - !set_storage { kind: local, key: '{app}', value: '{app}' }
 ";
         let vars = Vars(IndexMap::from([
             ("url".to_string(), "http://localhost".to_string()),
@@ -342,6 +977,9 @@ mod step_tests {
         let s6 = &expanded_steps[5];
         let s7 = &expanded_steps[6];
         let s8 = &expanded_steps[7];
+        let s9 = &expanded_steps[8];
+        let s10 = &expanded_steps[9];
+        let s11 = &expanded_steps[10];
         assert_eq!(Step::Goto("http://localhost".to_string()), *s1);
         assert_eq!(Step::Click("e2e".to_string()), *s2);
         assert_eq!(Step::Focus("e2e".to_string()), *s3);
@@ -352,7 +990,15 @@ mod step_tests {
             },
             *s4
         );
-        assert_eq!(Step::ScreenShot("e2e".to_string()), *s5);
+        assert_eq!(
+            Step::ScreenShot(ScreenShot {
+                path: "e2e".to_string(),
+                selector: None,
+                full_page: false,
+                mask: None,
+            }),
+            *s5
+        );
         assert_eq!(
             Step::WaitDisplayed {
                 selector: "e2e".to_string(),
@@ -364,7 +1010,12 @@ mod step_tests {
         assert_eq!(
             Step::TaskRun {
                 id: "login".to_string(),
-                args: Some(vec!["admin".to_string(), "e2e".to_string()]),
+                args: Some(TaskArgs::Positional(vec![
+                    "admin".to_string(),
+                    "e2e".to_string()
+                ])),
+                store_as: None,
+                outputs: None,
             },
             *s7
         );
@@ -376,5 +1027,199 @@ mod step_tests {
             },
             *s8
         );
+        assert_eq!(Step::AcceptPrompt("e2e".to_string()), *s9);
+        assert_eq!(
+            Step::SetCookie {
+                name: "e2e".to_string(),
+                value: "e2e".to_string(),
+                path: None,
+                domain: Some("e2e".to_string()),
+                secure: None,
+                expiry: None,
+            },
+            *s10
+        );
+        assert_eq!(
+            Step::SetStorage {
+                kind: StorageKind::Local,
+                key: "e2e".to_string(),
+                value: "e2e".to_string(),
+            },
+            *s11
+        );
+    }
+
+    #[test]
+    fn test_expand_templates() {
+        let env = |name: &str| (name == "E2E_TEST_API_HOST").then(|| "api.example.com".to_string());
+        let vars = Vars(IndexMap::from([
+            ("user".to_string(), "admin".to_string()),
+            ("price".to_string(), "10".to_string()),
+        ]));
+
+        assert_eq!(
+            "https://api.example.com/api.example.com",
+            template::render_with(
+                "https://{env:E2E_TEST_API_HOST}/${E2E_TEST_API_HOST}",
+                &vars,
+                &env,
+                None
+            )
+        );
+        // `$` is kept when the environment does not have the variable
+        assert_eq!("$10", template::render_with("${price}", &vars, &env, None));
+        assert_eq!(
+            "admin {role:-guest} {env:E2E_TEST_UNSET:-x}",
+            template::render_with(
+                "{user:-nobody} {role:-guest} {env:E2E_TEST_UNSET:-x}",
+                &vars,
+                &env,
+                None
+            )
+        );
+        assert_eq!(
+            "admin guest x",
+            template::render_with(
+                "{user:-nobody} {role:-guest} {env:E2E_TEST_UNSET:-x}",
+                &vars,
+                &env,
+                Some(&Builtins::default())
+            )
+        );
+        assert_eq!(
+            "{role}",
+            expand_with_defaults("{role}", &vars, &Builtins::default())
+        );
+    }
+
+    #[test]
+    fn test_expand_vars_in_blocks() {
+        let yaml = "
+ - !for_each
+   items: [ '{app}', b ]
+   as: item
+   steps:
+     - !click '{item} {app}'
+ - !for_each { items: '{list}', as: item, steps: [] }
+";
+        let vars = Vars(IndexMap::from([
+            ("app".to_string(), "e2e".to_string()),
+            ("list".to_string(), "[\"x\", 1]".to_string()),
+        ]));
+        let steps: Vec<Step> = serde_yaml::from_str(yaml).unwrap();
+        let expanded_steps: Vec<Step> = steps.iter().map(|x| x.expand_vars(&vars)).collect();
+        assert_eq!(
+            Step::ForEach {
+                items: Items::List(vec!["e2e".to_string(), "b".to_string()]),
+                store_as: "item".to_string(),
+                steps: vec![Step::Click("{item} e2e".to_string())],
+            },
+            expanded_steps[0]
+        );
+        assert_eq!(
+            Step::ForEach {
+                items: Items::Var("[\"x\", 1]".to_string()),
+                store_as: "item".to_string(),
+                steps: vec![],
+            },
+            expanded_steps[1]
+        );
+    }
+
+    #[test]
+    fn test_screenshot_mismatch() {
+        let err = StepError {
+            kind: StepErrorKind::ScreenshotMismatch(
+                "header".to_string(),
+                0.125,
+                0.01,
+                vec![
+                    "artifacts/header-expected.png".to_string(),
+                    "artifacts/header-actual.png".to_string(),
+                    "artifacts/header-diff.png".to_string(),
+                ],
+            ),
+        };
+        assert_eq!(
+            "\tscreenshot 'header' differs from baseline by 12.50% (threshold 1.00%)
+\t\twrote artifacts/header-expected.png
+\t\twrote artifacts/header-actual.png
+\t\twrote artifacts/header-diff.png
+",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let yaml = "
+ - !send_keys { selector: '#password', value: passw0rd }
+ - !accept_alert
+ - !goto 'http://localhost'
+";
+        let steps: Vec<Step> = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!("!send_keys '#password'", steps[0].describe());
+        assert_eq!("!accept_alert", steps[1].describe());
+        assert_eq!("!goto", steps[2].describe());
+    }
+
+    #[test]
+    fn test_loop_var_shadows() {
+        let yaml = "
+ - !click '{item}'
+ - !for_each
+   items: [ '{item}', b ]
+   as: item
+   steps:
+     - !click '{item} {app}'
+     - !repeat { times: 2, as: app, steps: [ !click '{item} {app}' ] }
+";
+        let vars = Vars(IndexMap::from([
+            ("item".to_string(), "global".to_string()),
+            ("app".to_string(), "e2e".to_string()),
+        ]));
+        let steps: Vec<Step> = serde_yaml::from_str(yaml).unwrap();
+        let expanded_steps: Vec<Step> = steps.iter().map(|x| x.expand_vars(&vars)).collect();
+        assert_eq!(Step::Click("global".to_string()), expanded_steps[0]);
+        assert_eq!(
+            Step::ForEach {
+                items: Items::List(vec!["global".to_string(), "b".to_string()]),
+                store_as: "item".to_string(),
+                steps: vec![
+                    Step::Click("{item} e2e".to_string()),
+                    Step::Repeat {
+                        times: 2,
+                        store_as: Some("app".to_string()),
+                        steps: vec![Step::Click("{item} {app}".to_string())],
+                    },
+                ],
+            },
+            expanded_steps[1]
+        );
+    }
+
+    #[test]
+    fn test_retry_attempts() {
+        let step: Step =
+            serde_yaml::from_str("!retry { attempts: 2, steps: [ !click a ] }").unwrap();
+        assert_eq!(
+            Step::Retry {
+                attempts: NonZeroU64::new(2).unwrap(),
+                backoff_ms: None,
+                steps: vec![Step::Click("a".to_string())],
+            },
+            step
+        );
+        assert!(serde_yaml::from_str::<Step>("!retry { attempts: 0, steps: [] }").is_err());
+    }
+
+    #[test]
+    fn test_resolve_items() {
+        let items = Items::List(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(vec!["a", "b"], items.resolve().ok().unwrap());
+        let items = Items::Var("[\"a\", 1, true]".to_string());
+        assert_eq!(vec!["a", "1", "true"], items.resolve().ok().unwrap());
+        let items = Items::Var("a,b".to_string());
+        assert!(items.resolve().is_err());
     }
 }
@@ -0,0 +1,92 @@
+use indexmap::IndexMap;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use thirtyfour::error::WebDriverResult;
+use thirtyfour::WebDriver;
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    Local,
+    Session,
+}
+
+impl StorageKind {
+    fn object(&self) -> &str {
+        match self {
+            StorageKind::Local => "localStorage",
+            StorageKind::Session => "sessionStorage",
+        }
+    }
+}
+
+pub async fn get_item(
+    driver: &WebDriver,
+    kind: &StorageKind,
+    key: &str,
+) -> WebDriverResult<Option<String>> {
+    let ret = driver
+        .execute(
+            "return window[arguments[0]].getItem(arguments[1]);",
+            vec![kind.object().into(), key.into()],
+        )
+        .await?;
+    Ok(ret.json().as_str().map(|x| x.to_string()))
+}
+
+pub async fn set_item(
+    driver: &WebDriver,
+    kind: &StorageKind,
+    key: &str,
+    value: &str,
+) -> WebDriverResult<()> {
+    driver
+        .execute(
+            "window[arguments[0]].setItem(arguments[1], arguments[2]);",
+            vec![kind.object().into(), key.into(), value.into()],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn remove_item(driver: &WebDriver, kind: &StorageKind, key: &str) -> WebDriverResult<()> {
+    driver
+        .execute(
+            "window[arguments[0]].removeItem(arguments[1]);",
+            vec![kind.object().into(), key.into()],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn clear(driver: &WebDriver, kind: &StorageKind) -> WebDriverResult<()> {
+    driver
+        .execute(
+            "window[arguments[0]].clear();",
+            vec![Value::from(kind.object())],
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_all(
+    driver: &WebDriver,
+    kind: &StorageKind,
+) -> WebDriverResult<IndexMap<String, String>> {
+    let ret = driver
+        .execute(
+            r#"
+            const storage = window[arguments[0]];
+            const items = {};
+            for (let i = 0; i < storage.length; i++) {
+                const key = storage.key(i);
+                items[key] = storage.getItem(key);
+            }
+            return items;
+            "#,
+            vec![Value::from(kind.object())],
+        )
+        .await?;
+    ret.convert()
+}
@@ -0,0 +1,414 @@
+use std::cell::RefCell;
+
+use indexmap::{IndexMap, IndexSet};
+
+use super::builtin::Builtins;
+use super::step::Step;
+use super::task::{self, Tasks};
+use super::template::{self, Reference};
+use super::E2eYaml;
+
+#[derive(Debug, PartialEq)]
+pub struct StrictError {
+    pub kind: StrictErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StrictErrorKind {
+    /// Holds the configuration file and the references found in it.
+    UndefinedVars(String, Vec<UndefinedVar>),
+}
+
+impl std::error::Error for StrictError {}
+
+impl std::fmt::Display for StrictError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            StrictErrorKind::UndefinedVars(file, vars) => {
+                writeln!(f, "undefined variables in {}", file)?;
+                for var in vars {
+                    writeln!(f, "\t'{}' at {} of {}", var.name, var.location, var.caller)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A `{name}` reference that no scope defines.
+#[derive(Debug, PartialEq)]
+pub struct UndefinedVar {
+    /// The scenario or task the step belongs to.
+    pub caller: String,
+    pub location: String,
+    pub name: String,
+}
+
+/// Finds the variable references of every scenario and task that would be left
+/// as is at run time. Variables stored by steps count as defined wherever they are
+/// used in the same scenario or task, regardless of the order of the steps.
+/// Tasks may also use the variables of the scenarios and tasks running them.
+pub fn find_undefined_vars(config: &E2eYaml) -> Vec<UndefinedVar> {
+    let tasks = config.tasks.as_ref();
+    let global: IndexSet<String> = config.global_vars().0.into_keys().collect();
+
+    // variables visible to each task, from the scenarios and tasks running it
+    let mut inherited: IndexMap<&str, IndexSet<String>> = IndexMap::new();
+    let mut result: Vec<UndefinedVar> = Vec::new();
+    if let Some(steps) = &config.before_all {
+        let mut scope = global.clone();
+        Step::collect_stored_vars(steps, tasks, &mut scope);
+        check_steps("before_all", "steps", steps, &scope, &mut result);
+        if let Some(tasks) = tasks {
+            inherit_scope(steps, &scope, tasks, &mut inherited, &mut Vec::new());
+        }
+    }
+
+    for (id, scenario) in &config.scenarios.0 {
+        let mut scope = global.clone();
+        if let Some(vars) = &scenario.vars {
+            scope.extend(vars.0.keys().cloned());
+        }
+        Step::collect_stored_vars(&scenario.steps, tasks, &mut scope);
+        check_steps(
+            &format!("scenario '{}'", id),
+            "steps",
+            &scenario.steps,
+            &scope,
+            &mut result,
+        );
+        if let Some(tasks) = tasks {
+            inherit_scope(
+                &scenario.steps,
+                &scope,
+                tasks,
+                &mut inherited,
+                &mut Vec::new(),
+            );
+        }
+    }
+
+    if let Some(tasks) = tasks {
+        for (id, task) in &tasks.0 {
+            let mut scope = global.clone();
+            if let Some(from_callers) = inherited.get(id.as_str()) {
+                scope.extend(from_callers.iter().cloned());
+            }
+            for param in task.arg_names.as_deref().unwrap_or_default() {
+                scope.insert(param.name().to_string());
+            }
+            if let Some(vars) = &task.vars {
+                scope.extend(vars.0.keys().cloned());
+            }
+            Step::collect_stored_vars(&task.steps, Some(tasks), &mut scope);
+            check_steps(
+                &format!("task '{}'", id),
+                "steps",
+                &task.steps,
+                &scope,
+                &mut result,
+            );
+        }
+    }
+    result
+}
+
+/// Adds `scope` to the variables visible to the tasks run by `steps`, and the
+/// variables stored by these tasks to the ones visible to the tasks they run.
+/// `stack` holds the tasks being visited, so that circular calls end.
+fn inherit_scope<'a>(
+    steps: &'a [Step],
+    scope: &IndexSet<String>,
+    tasks: &'a Tasks,
+    inherited: &mut IndexMap<&'a str, IndexSet<String>>,
+    stack: &mut Vec<&'a str>,
+) {
+    let mut ids: Vec<&str> = Vec::new();
+    task::collect_dependencies(steps, &mut ids);
+    for id in ids {
+        let Some(task) = tasks.0.get(id) else {
+            continue;
+        };
+        if stack.contains(&id) {
+            continue;
+        }
+        inherited
+            .entry(id)
+            .or_default()
+            .extend(scope.iter().cloned());
+        let mut passed = scope.clone();
+        Step::collect_stored_vars(&task.steps, Some(tasks), &mut passed);
+        stack.push(id);
+        inherit_scope(&task.steps, &passed, tasks, inherited, stack);
+        stack.pop();
+    }
+}
+
+fn check_steps(
+    caller: &str,
+    path: &str,
+    steps: &[Step],
+    scope: &IndexSet<String>,
+    result: &mut Vec<UndefinedVar>,
+) {
+    for (i, step) in steps.iter().enumerate() {
+        let location = format!("{}[{}]", path, i);
+
+        // references of the nested steps are reported at their own location
+        let mut own = references(step);
+        for (_, nested) in step.nested_steps() {
+            for name in nested.iter().flat_map(references) {
+                if let Some(pos) = own.iter().position(|x| *x == name) {
+                    own.remove(pos);
+                }
+            }
+        }
+        let own: IndexSet<String> = own
+            .iter()
+            .filter(|x| !is_defined(x, scope))
+            .map(|x| x.raw.to_string())
+            .collect();
+        for name in own {
+            result.push(UndefinedVar {
+                caller: caller.to_string(),
+                location: location.to_string(),
+                name,
+            });
+        }
+
+        for (key, nested) in step.nested_steps() {
+            check_steps(
+                caller,
+                &format!("{}.{}", location, key),
+                nested,
+                scope,
+                result,
+            );
+        }
+    }
+}
+
+/// References found in the strings of the step, including its nested steps.
+fn references(step: &Step) -> Vec<Reference> {
+    let found: RefCell<Vec<Reference>> = RefCell::new(Vec::new());
+    step.map_strings(&|x| {
+        found.borrow_mut().extend(template::references(x));
+        x.to_string()
+    });
+    found.into_inner()
+}
+
+/// References with a default are always defined. A reference with an unknown
+/// filter is never defined, so that the filter is reported. So are misspelled
+/// built-ins and built-ins with invalid arguments.
+fn is_defined(reference: &Reference, scope: &IndexSet<String>) -> bool {
+    if !reference.unknown_filters().is_empty() {
+        return false;
+    }
+    if let Some((name, args)) = reference.builtin() {
+        return Builtins::is_valid(name, &args);
+    }
+    if reference.default.is_some() {
+        return true;
+    }
+    match reference.key.strip_prefix("env:") {
+        Some(env) => std::env::var(env).is_ok(),
+        None => scope.contains(&reference.key),
+    }
+}
+
+#[cfg(test)]
+mod strict_tests {
+    use super::*;
+
+    fn find(yaml: &str) -> Vec<(String, String, String)> {
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        find_undefined_vars(&config.expand())
+            .into_iter()
+            .map(|x| (x.caller, x.location, x.name))
+            .collect()
+    }
+
+    fn undefined(caller: &str, location: &str, name: &str) -> (String, String, String) {
+        (caller.to_string(), location.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn test_find_undefined_vars() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+vars:
+  root: http://localhost
+
+tasks:
+  open_repo:
+    arg_names: [ owner, { name: repo, default: e2e } ]
+    vars:
+      host: github.com
+    steps:
+      - !goto 'https://{host}/{owner}/{repo}/{branch}'
+      - !click '#{lang}'
+      - !click '#{tag}'
+
+scenarios:
+  s1:
+    name: s1
+    vars:
+      lang: en
+    steps:
+      - !execute_script { script: 'return 1', as: branch }
+      - !goto '{root}/{repo-name-cs}'
+      - !execute_script { script: 'if (x) { return {}; }' }
+      - !if
+        condition: !exists '#{lang}'
+        then:
+          - !click '#{missing:-none}'
+          - !click '#{missing}'
+      - !task_run { id: open_repo, args: [ tkr66 ] }
+";
+        assert_eq!(
+            vec![
+                undefined("scenario 's1'", "steps[1]", "repo-name-cs"),
+                undefined("scenario 's1'", "steps[3].then[1]", "missing"),
+                undefined("task 'open_repo'", "steps[2]", "tag"),
+            ],
+            find(yaml)
+        );
+    }
+
+    #[test]
+    fn test_stored_vars_and_outputs() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+tasks:
+  create_order:
+    outputs: [ order_id ]
+    steps:
+      - !get_cookie { name: order, as: order_id }
+
+scenarios:
+  s1:
+    name: s1
+    steps:
+      - !task_run { id: create_order, as: order }
+      - !goto 'http://localhost/orders/{order}'
+      - !for_each
+        items: [ a, b ]
+        as: item
+        steps:
+          - !click '#{item}'
+      - !click '#{order_id}'
+";
+        assert_eq!(
+            vec![undefined("scenario 's1'", "steps[3]", "order_id")],
+            find(yaml)
+        );
+    }
+
+    #[test]
+    fn test_task_scope() {
+        let yaml = "
+driver:
+  host: localhost
+  port: 4444
+  headless: true
+  window: { x: 0, y: 0, width: 1920, height: 1080 }
+
+tasks:
+  search:
+    steps:
+      - !task_run { id: open }
+      - !goto '{root}/{query}'
+  open:
+    steps:
+      - !click '#{token}'
+      - !click '#{lang}'
+  unused:
+    steps:
+      - !click '#{lang}'
+
+scenarios:
+  s1:
+    name: s1
+    vars:
+      root: http://localhost
+      lang: en
+    steps:
+      - !get_cookie { name: token, as: token }
+      - !task_run { id: search }
+  s2:
+    name: s2
+    vars:
+      query: e2e
+    steps:
+      - !click '#top'
+";
+        assert_eq!(
+            vec![
+                undefined("task 'search'", "steps[1]", "query"),
+                undefined("task 'unused'", "steps[0]", "lang"),
+            ],
+            find(yaml)
+        );
+    }
+
+    #[test]
+    fn test_instances_and_before_all() {
+        let yaml = "
+driver: { host: localhost, port: 4444, headless: true, window: { x: 0, y: 0, width: 1, height: 1 } }
+before_all:
+  - !goto 'http://localhost/{before}'
+scenarios:
+  checkout:
+    name: checkout
+    data: [ { lang: en } ]
+    steps:
+      - !send_keys { selector: '#q', value: '{lang}-{missing}' }
+";
+        let config: E2eYaml = serde_yaml::from_str(yaml).unwrap();
+        let config = config
+            .instantiate(std::path::Path::new(""))
+            .unwrap()
+            .expand();
+        let undefined = find_undefined_vars(&config);
+        assert_eq!(
+            "undefined variables in e2e.yaml\n\t'before' at steps[0] of before_all\n\t'missing' at steps[0] of scenario 'checkout[en]'\n",
+            StrictError {
+                kind: StrictErrorKind::UndefinedVars("e2e.yaml".to_string(), undefined)
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_is_defined() {
+        let scope: IndexSet<String> = IndexSet::from(["user".to_string()]);
+        let is_defined = |x: &str| {
+            let references = template::references(x);
+            super::is_defined(&references[0], &scope)
+        };
+        assert!(is_defined("{user}"));
+        assert!(is_defined("{user|upper}"));
+        assert!(!is_defined("{user|reverse}"));
+        assert!(!is_defined("{usr}"));
+        assert!(is_defined("{usr:-guest}"));
+        assert!(!is_defined("{env:E2E_STRICT_TEST_UNSET}"));
+        assert!(is_defined("{$uuid}"));
+        assert!(is_defined("{$random_int:-5:5}"));
+        assert!(!is_defined("{$random_int:5}"));
+        assert!(!is_defined("{$nonce}"));
+        assert!(is_defined("{$fake:name:ja_JP}"));
+        assert!(!is_defined("{$fake:name:xx_XX}"));
+        assert!(template::references("{ return 1; }").is_empty());
+    }
+}
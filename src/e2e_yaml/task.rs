@@ -3,6 +3,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use super::step::Step;
+use super::var::Vars;
 
 #[derive(Debug, PartialEq)]
 pub struct TaskError {
@@ -13,6 +14,9 @@ pub struct TaskError {
 pub enum TaskErrorKind {
     CircularDependenciesDetected(Vec<String>),
     TaskNotFound(String),
+    /// A call site does not match the signature of the task.
+    /// Holds the caller, the step location, the task id and the reason.
+    InvalidCall(String, String, String, String),
 }
 
 impl std::error::Error for TaskError {}
@@ -26,6 +30,13 @@ impl std::fmt::Display for TaskError {
             TaskErrorKind::TaskNotFound(id) => {
                 writeln!(f, "task with id '{}' not found  in configuration", id)
             }
+            TaskErrorKind::InvalidCall(caller, location, id, reason) => {
+                writeln!(
+                    f,
+                    "invalid call of task '{}' at {} of {}. {}",
+                    id, location, caller, reason
+                )
+            }
         }
     }
 }
@@ -67,25 +78,133 @@ impl Tasks {
                 self.detect_circular_dependencies_recursive(dep_id, visited)?;
             }
         }
+        // `visited` is the current call path, a task may be reached again by another one
+        visited.pop();
         Ok(())
     }
+
+    /// Checks that every `!task_run` in `steps`, including the nested ones,
+    /// refers to an existing task with arguments matching its signature.
+    /// `caller` names the scenario or task the steps belong to.
+    pub fn validate_calls(
+        tasks: Option<&Tasks>,
+        caller: &str,
+        steps: &[Step],
+    ) -> Result<(), TaskError> {
+        validate_calls_recursive(tasks, caller, "steps", steps)
+    }
+}
+
+fn validate_calls_recursive(
+    tasks: Option<&Tasks>,
+    caller: &str,
+    path: &str,
+    steps: &[Step],
+) -> Result<(), TaskError> {
+    for (i, step) in steps.iter().enumerate() {
+        let location = format!("{}[{}]", path, i);
+        if let Step::TaskRun {
+            id,
+            args,
+            store_as,
+            outputs,
+        } = step
+        {
+            let invalid_call = |reason: String| TaskError {
+                kind: TaskErrorKind::InvalidCall(
+                    caller.to_string(),
+                    location.to_string(),
+                    id.to_string(),
+                    reason,
+                ),
+            };
+            match tasks.and_then(|x| x.0.get(id)) {
+                Some(task) => {
+                    task.bind_args(args.as_ref()).map_err(invalid_call)?;
+                    task.bind_outputs(store_as.as_deref(), outputs.as_ref())
+                        .map_err(invalid_call)?;
+                }
+                None => return Err(invalid_call("task not found".to_string())),
+            }
+        }
+        for (key, nested) in step.nested_steps() {
+            validate_calls_recursive(tasks, caller, &format!("{}.{}", location, key), nested)?;
+        }
+    }
+    Ok(())
+}
+
+/// A task parameter. Either a bare name, which is required,
+/// or a map giving a default value or marking it as optional.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Param {
+    Name(String),
+    Spec {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        default: Option<String>,
+        /// An optional parameter without default is bound to an empty string.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        optional: bool,
+    },
+}
+
+impl Param {
+    pub fn name(&self) -> &str {
+        match self {
+            Param::Name(name) => name,
+            Param::Spec { name, .. } => name,
+        }
+    }
+
+    /// The value bound when the caller omits the argument, `None` if it is required.
+    pub fn default_value(&self) -> Option<&str> {
+        match self {
+            Param::Name(_) => None,
+            Param::Spec {
+                default: Some(default),
+                ..
+            } => Some(default),
+            Param::Spec { optional: true, .. } => Some(""),
+            Param::Spec { .. } => None,
+        }
+    }
+}
+
+/// Arguments of `!task_run`, either in the order of `arg_names` or by name.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TaskArgs {
+    Positional(Vec<String>),
+    Named(IndexMap<String, String>),
+}
+
+impl TaskArgs {
+    pub fn map_strings<F: Fn(&str) -> String>(&self, f: F) -> Self {
+        match self {
+            TaskArgs::Positional(args) => TaskArgs::Positional(args.iter().map(|x| f(x)).collect()),
+            TaskArgs::Named(args) => {
+                TaskArgs::Named(args.iter().map(|(k, v)| (k.to_string(), f(v))).collect())
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Task {
-    pub arg_names: Option<Vec<String>>,
+    pub arg_names: Option<Vec<Param>>,
+    /// Variables set by the steps that are handed back to the caller.
+    pub outputs: Option<Vec<String>>,
+    /// Variables visible to the steps of the task only, overridden by its args.
+    pub vars: Option<Vars>,
     pub steps: Vec<Step>,
 }
 
 impl Task {
     pub fn list_dependencies(&self) -> Option<Vec<&str>> {
         let mut deps: Vec<&str> = Vec::new();
-        for step in &self.steps {
-            match step {
-                Step::TaskRun { id, args: _ } => deps.push(id),
-                _ => continue,
-            }
-        }
+        collect_dependencies(&self.steps, &mut deps);
         if deps.is_empty() {
             None
         } else {
@@ -93,18 +212,95 @@ impl Task {
         }
     }
 
-    pub fn expand_args(&self, args: Option<&[&str]>) -> Vec<Step> {
-        let mut result: Vec<Step> = Vec::new();
-        for step in &self.steps {
-            let mut cloned = step.clone();
-            if let Some(names) = &self.arg_names {
-                (0..names.len()).for_each(|i| {
-                    cloned = cloned.expand_var(names[i].as_str(), args.unwrap()[i]);
-                });
+    /// Binds `args` to the parameters of the task, filling in the defaults
+    /// of the omitted ones. Returns the reason if they do not match.
+    pub fn bind_args(&self, args: Option<&TaskArgs>) -> Result<IndexMap<String, String>, String> {
+        let params = self.arg_names.as_deref().unwrap_or_default();
+        let mut bound: IndexMap<String, String> = IndexMap::new();
+        match args {
+            None => {}
+            Some(TaskArgs::Positional(values)) => {
+                if values.len() > params.len() {
+                    return Err(format!(
+                        "expected at most {} args, got {}",
+                        params.len(),
+                        values.len()
+                    ));
+                }
+                for (param, value) in params.iter().zip(values) {
+                    bound.insert(param.name().to_string(), value.to_string());
+                }
+            }
+            Some(TaskArgs::Named(values)) => {
+                for (name, value) in values {
+                    if !params.iter().any(|x| x.name() == name) {
+                        return Err(format!("unknown arg '{}'", name));
+                    }
+                    bound.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+
+        let mut result: IndexMap<String, String> = IndexMap::new();
+        for param in params {
+            let value = match (bound.get(param.name()), param.default_value()) {
+                (Some(value), _) => value.to_string(),
+                (None, Some(default)) => default.to_string(),
+                (None, None) => return Err(format!("missing arg '{}'", param.name())),
+            };
+            result.insert(param.name().to_string(), value);
+        }
+        Ok(result)
+    }
+
+    /// Returns the caller variable each output is stored in. Outputs keep their
+    /// names unless `store_as` or a mapping in `outputs` is given.
+    pub fn bind_outputs(
+        &self,
+        store_as: Option<&str>,
+        outputs: Option<&IndexMap<String, String>>,
+    ) -> Result<IndexMap<String, String>, String> {
+        let declared = self.outputs.as_deref().unwrap_or_default();
+        match (store_as, outputs) {
+            (Some(_), Some(_)) => Err("'as' and 'outputs' cannot be used together".to_string()),
+            (Some(var), None) => match declared {
+                [output] => Ok(IndexMap::from([(output.to_string(), var.to_string())])),
+                _ => Err(format!(
+                    "'as' requires exactly one output, got {}",
+                    declared.len()
+                )),
+            },
+            (None, Some(mapping)) => {
+                for output in mapping.keys() {
+                    if !declared.contains(output) {
+                        return Err(format!("unknown output '{}'", output));
+                    }
+                }
+                Ok(mapping.clone())
+            }
+            (None, None) => Ok(declared
+                .iter()
+                .map(|x| (x.to_string(), x.to_string()))
+                .collect()),
+        }
+    }
+
+    pub fn expand_args(&self, args: Option<&TaskArgs>) -> Result<Vec<Step>, String> {
+        let bound = Vars(self.bind_args(args)?);
+        Ok(self.steps.iter().map(|x| x.expand_vars(&bound)).collect())
+    }
+}
+
+pub fn collect_dependencies<'a>(steps: &'a [Step], deps: &mut Vec<&'a str>) {
+    for step in steps {
+        match step {
+            Step::TaskRun { id, .. } => deps.push(id),
+            _ => {
+                for (_, nested) in step.nested_steps() {
+                    collect_dependencies(nested, deps);
+                }
             }
-            result.push(cloned);
         }
-        result
     }
 }
 
@@ -125,8 +321,8 @@ login:
 ";
         let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
         let t1 = &tasks.0["login"];
-        let args = ["#input", "hello world"];
-        let expanded_t1 = t1.expand_args(Some(&args));
+        let args = TaskArgs::Positional(vec!["#input".to_string(), "hello world".to_string()]);
+        let expanded_t1 = t1.expand_args(Some(&args)).unwrap();
         assert_eq!(
             vec![
                 Step::SendKeys {
@@ -142,6 +338,141 @@ login:
         );
     }
 
+    #[test]
+    fn test_bind_args() {
+        let yaml = "
+arg_names:
+  - owner
+  - { name: repo, default: e2e }
+  - { name: branch, optional: true }
+steps: []
+";
+        let task: Task = serde_yaml::from_str(yaml).unwrap();
+        let bound = |pairs: &[(&str, &str)]| -> IndexMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        let args = TaskArgs::Positional(vec!["tkr66".to_string()]);
+        assert_eq!(
+            Ok(bound(&[
+                ("owner", "tkr66"),
+                ("repo", "e2e"),
+                ("branch", "")
+            ])),
+            task.bind_args(Some(&args))
+        );
+
+        let args: TaskArgs = serde_yaml::from_str("{ branch: main, owner: tkr66 }").unwrap();
+        assert_eq!(
+            Ok(bound(&[
+                ("owner", "tkr66"),
+                ("repo", "e2e"),
+                ("branch", "main")
+            ])),
+            task.bind_args(Some(&args))
+        );
+
+        assert_eq!(Err("missing arg 'owner'".to_string()), task.bind_args(None));
+        let args: TaskArgs = serde_yaml::from_str("{ owner: tkr66, tag: v1 }").unwrap();
+        assert_eq!(
+            Err("unknown arg 'tag'".to_string()),
+            task.bind_args(Some(&args))
+        );
+        let args: TaskArgs = serde_yaml::from_str("[ a, b, c, d ]").unwrap();
+        assert_eq!(
+            Err("expected at most 3 args, got 4".to_string()),
+            task.bind_args(Some(&args))
+        );
+    }
+
+    #[test]
+    fn test_bind_outputs() {
+        let yaml = "
+outputs: [ order_id, total ]
+steps: []
+";
+        let task: Task = serde_yaml::from_str(yaml).unwrap();
+        let bound = |pairs: &[(&str, &str)]| -> IndexMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            Ok(bound(&[("order_id", "order_id"), ("total", "total")])),
+            task.bind_outputs(None, None)
+        );
+        let mapping = bound(&[("order_id", "first_order")]);
+        assert_eq!(Ok(mapping.clone()), task.bind_outputs(None, Some(&mapping)));
+        assert_eq!(
+            Err("unknown output 'id'".to_string()),
+            task.bind_outputs(None, Some(&bound(&[("id", "x")])))
+        );
+        assert_eq!(
+            Err("'as' requires exactly one output, got 2".to_string()),
+            task.bind_outputs(Some("order"), None)
+        );
+
+        let yaml = "
+outputs: [ customer_no ]
+steps: []
+";
+        let task: Task = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            Ok(bound(&[("customer_no", "customer")])),
+            task.bind_outputs(Some("customer"), None)
+        );
+    }
+
+    #[test]
+    fn test_validate_calls() {
+        let yaml = "
+login:
+  arg_names: [ user, password ]
+  steps:
+    - !send_keys { selector: '#user', value: '{user}' }
+";
+        let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
+        let steps: Vec<Step> = serde_yaml::from_str(
+            "
+- !task_run { id: login, args: { user: admin, password: pw } }
+- !if
+  condition: !exists '#login'
+  then:
+    - !goto localhost
+  else:
+    - !task_run { id: login, args: [ admin ] }
+",
+        )
+        .unwrap();
+        assert!(Tasks::validate_calls(Some(&tasks), "scenario 's1'", &steps[..1]).is_ok());
+        assert_eq!(
+            Err(TaskError {
+                kind: TaskErrorKind::InvalidCall(
+                    "scenario 's1'".to_string(),
+                    "steps[1].else[0]".to_string(),
+                    "login".to_string(),
+                    "missing arg 'password'".to_string(),
+                )
+            }),
+            Tasks::validate_calls(Some(&tasks), "scenario 's1'", &steps)
+        );
+        assert_eq!(
+            Err(TaskError {
+                kind: TaskErrorKind::InvalidCall(
+                    "scenario 's1'".to_string(),
+                    "steps[0]".to_string(),
+                    "login".to_string(),
+                    "task not found".to_string(),
+                )
+            }),
+            Tasks::validate_calls(None, "scenario 's1'", &steps)
+        );
+    }
+
     #[test]
     fn test_detect_mutual_dependencies() {
         let yaml = "
@@ -226,6 +557,72 @@ t3:
         );
     }
 
+    #[test]
+    fn test_detect_dependencies_in_branches() {
+        let yaml = "
+t1:
+  steps:
+    - !if
+      condition: !exists '#banner'
+      then:
+        - !goto localhost
+      else:
+        - !task_run { id: t2 }
+
+t2:
+  steps:
+    - !if
+      condition: !exists '#banner'
+      then:
+        - !task_run { id: t1 }
+";
+        let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(Some(vec!["t2"]), tasks.0["t1"].list_dependencies());
+        let res = tasks.detect_circular_dependencies().err();
+        assert_eq!(
+            Some(TaskError {
+                kind: TaskErrorKind::CircularDependenciesDetected(vec![
+                    "t1".to_string(),
+                    "t2".to_string(),
+                    "t1".to_string(),
+                ])
+            }),
+            res
+        );
+    }
+
+    #[test]
+    fn test_list_dependencies_in_blocks() {
+        let yaml = "
+t1:
+  steps:
+    - !repeat
+      times: 2
+      steps:
+        - !task_run { id: t2 }
+    - !for_each
+      items: [ a, b ]
+      as: item
+      steps:
+        - !task_run { id: t3 }
+    - !while
+      condition: !exists '.next'
+      max_iterations: 10
+      steps:
+        - !task_run { id: t4 }
+    - !retry
+      attempts: 3
+      backoff_ms: 100
+      steps:
+        - !task_run { id: t5 }
+";
+        let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            Some(vec!["t2", "t3", "t4", "t5"]),
+            tasks.0["t1"].list_dependencies()
+        );
+    }
+
     #[test]
     fn test_no_circular_dependencies() {
         let yaml = "
@@ -241,6 +638,47 @@ t2:
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_same_task_in_both_branches() {
+        let yaml = "
+t1:
+  steps:
+    - !if
+      condition: !exists '#login'
+      then:
+        - !task_run { id: login }
+      else:
+        - !goto localhost
+        - !task_run { id: login }
+login:
+  steps:
+    - !goto localhost
+";
+        let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(Ok(()), tasks.detect_circular_dependencies());
+    }
+
+    #[test]
+    fn test_diamond_dependencies() {
+        let yaml = "
+t1:
+  steps:
+    - !task_run { id: t2 }
+    - !task_run { id: t3 }
+t2:
+  steps:
+    - !task_run { id: t4 }
+t3:
+  steps:
+    - !task_run { id: t4 }
+t4:
+  steps:
+    - !goto localhost
+";
+        let tasks: Tasks = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(Ok(()), tasks.detect_circular_dependencies());
+    }
+
     #[test]
     fn test_task_not_found() {
         let yaml = "
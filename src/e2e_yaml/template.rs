@@ -0,0 +1,457 @@
+use super::builtin::Builtins;
+use super::var::{self, Env, Vars};
+
+const FILTERS: &[&str] = &["upper", "lower", "urlencode", "json"];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Token {
+    Text(String),
+    Ref(Reference),
+}
+
+/// A `{key:-default|filter}`, `${key}` or `{$builtin:arg}` in a template.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Reference {
+    /// Text between the braces.
+    pub raw: String,
+    /// Written as `${...}`, reading the environment before `vars`.
+    pub shell: bool,
+    pub key: String,
+    pub default: Option<String>,
+    pub filters: Vec<String>,
+}
+
+impl Reference {
+    fn new(raw: String, shell: bool) -> Self {
+        let mut parts = raw.split('|');
+        let head = parts.next().unwrap_or_default();
+        let filters: Vec<String> = parts.map(|x| x.trim().to_string()).collect();
+        // built-ins always have a value, so `:-` is taken as a negative argument
+        let (key, default) = match head.split_once(":-").filter(|_| !head.starts_with('$')) {
+            Some((key, default)) => (key.to_string(), Some(default.to_string())),
+            None => (head.to_string(), None),
+        };
+        Reference {
+            raw,
+            shell,
+            key,
+            default,
+            filters,
+        }
+    }
+
+    /// Braces around anything else than a name, such as the body of a
+    /// JavaScript function, are not a reference.
+    pub fn is_name(&self) -> bool {
+        match self.builtin() {
+            Some((name, _)) => is_name(name),
+            None => is_name(self.key.strip_prefix("env:").unwrap_or(&self.key)),
+        }
+    }
+
+    /// The name and arguments of a `{$name:arg:...}` built-in. The format of
+    /// `{$date:FORMAT}` is a single argument, colons included.
+    pub fn builtin(&self) -> Option<(&str, Vec<&str>)> {
+        let builtin = self.key.strip_prefix('$').filter(|_| !self.shell)?;
+        let (name, args) = match builtin.split_once(':') {
+            Some(("date", format)) => ("date", vec![format]),
+            Some((name, args)) => (name, args.split(':').collect()),
+            None => (builtin, Vec::new()),
+        };
+        Some((name, args))
+    }
+
+    pub fn unknown_filters(&self) -> Vec<&str> {
+        self.filters
+            .iter()
+            .map(|x| x.as_str())
+            .filter(|x| !FILTERS.contains(x))
+            .collect()
+    }
+
+    /// The reference as written in the template.
+    pub fn source(&self) -> String {
+        format!("{}{{{}}}", if self.shell { "$" } else { "" }, self.raw)
+    }
+
+    /// Returns the filtered value, `None` if the reference is missing from `vars`
+    /// and the environment, or is not a valid reference. Built-ins and defaults
+    /// are only resolved by the final pass, which has `builtins`.
+    pub fn resolve(&self, vars: &Vars, env: Env, builtins: Option<&Builtins>) -> Option<String> {
+        if !self.is_name() || !self.unknown_filters().is_empty() {
+            return None;
+        }
+        if let Some((name, args)) = self.builtin() {
+            let value = builtins?.evaluate(name, &args)?;
+            return Some(self.apply_filters(value));
+        }
+        let use_default = builtins.is_some();
+        if self.shell {
+            if let Some(value) = env(&self.key) {
+                return Some(self.apply_filters(value));
+            }
+        }
+        let value = match self.key.strip_prefix("env:") {
+            Some(name) => env(name),
+            None => vars.0.get(&self.key).cloned(),
+        };
+        let value = value.or(self.default.clone().filter(|_| use_default))?;
+        let value = self.apply_filters(value);
+        // the `$` of `${NAME}` is kept when NAME is not in the environment
+        Some(if self.shell {
+            format!("${}", value)
+        } else {
+            value
+        })
+    }
+
+    fn apply_filters(&self, value: String) -> String {
+        self.filters
+            .iter()
+            .fold(value, |value, filter| match filter.as_str() {
+                "upper" => value.to_uppercase(),
+                "lower" => value.to_lowercase(),
+                "urlencode" => urlencode(&value),
+                "json" => serde_json::Value::String(value).to_string(),
+                _ => value,
+            })
+    }
+}
+
+/// Splits `input` into text and references. `{{` and `}}` are literal braces,
+/// a `{` without closing brace is kept as text.
+pub fn parse(input: &str) -> Vec<Token> {
+    tokenize(input)
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect()
+}
+
+/// Splits `input` like `parse`, along with the text each token is written as.
+fn tokenize(input: &str) -> Vec<(Token, String)> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens: Vec<(Token, String)> = Vec::new();
+    let mut text = String::new();
+    let mut source = String::new();
+    // content and whether it started with `${`
+    let mut open: Option<(String, bool)> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        i += 1;
+        if let Some((raw, shell)) = &mut open {
+            match c {
+                '}' => {
+                    let reference = Reference::new(std::mem::take(raw), *shell);
+                    open = None;
+                    if !text.is_empty() {
+                        tokens.push((
+                            Token::Text(std::mem::take(&mut text)),
+                            std::mem::take(&mut source),
+                        ));
+                    }
+                    let written = reference.source();
+                    tokens.push((Token::Ref(reference), written));
+                }
+                '{' => {
+                    // not a reference, start over from this brace
+                    let written = format!("{}{}", if *shell { "${" } else { "{" }, raw);
+                    text.push_str(&written);
+                    source.push_str(&written);
+                    open = None;
+                    i -= 1;
+                }
+                _ => raw.push(c),
+            }
+            continue;
+        }
+        match (c, next) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                text.push(c);
+                source.push(c);
+                source.push(c);
+                i += 1;
+            }
+            ('{', _) => open = Some((String::new(), false)),
+            ('$', Some('{')) if chars.get(i + 1) != Some(&'{') => {
+                open = Some((String::new(), true));
+                i += 1;
+            }
+            _ => {
+                text.push(c);
+                source.push(c);
+            }
+        }
+    }
+    if let Some((raw, shell)) = open {
+        let written = format!("{}{}", if shell { "${" } else { "{" }, raw);
+        text.push_str(&written);
+        source.push_str(&written);
+    }
+    if !text.is_empty() {
+        tokens.push((Token::Text(text), source));
+    }
+    tokens
+}
+
+/// Valid references found in `input`.
+pub fn references(input: &str) -> Vec<Reference> {
+    parse(input)
+        .into_iter()
+        .filter_map(|x| match x {
+            Token::Ref(reference) if reference.is_name() => Some(reference),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Renders `input` in a single pass, so substituted values are never expanded again.
+///
+/// The result is a template again: references missing from `vars` are kept,
+/// defaults and built-ins included, and literal braces as well as braces in
+/// substituted values are escaped, so that a later pass renders them as is.
+pub fn render(input: &str, vars: &Vars) -> String {
+    render_with(input, vars, &var::process_env, None)
+}
+
+/// Renders `input` for the last time, right before a step uses it. Built-ins are
+/// evaluated, missing variables fall back to their defaults and escaped braces
+/// become literal ones.
+pub fn render_final(input: &str, vars: &Vars, builtins: &Builtins) -> String {
+    render_with(input, vars, &var::process_env, Some(builtins))
+}
+
+/// Renders `input` like `render`, or like `render_final` when `builtins` are given,
+/// reading the environment variables from `env`.
+pub fn render_with(input: &str, vars: &Vars, env: Env, builtins: Option<&Builtins>) -> String {
+    let mut out = String::new();
+    for token in parse(input) {
+        let text = match token {
+            Token::Text(text) => text,
+            Token::Ref(reference) => match reference.resolve(vars, env, builtins) {
+                Some(value) => value,
+                None => {
+                    out.push_str(&reference.source());
+                    continue;
+                }
+            },
+        };
+        if builtins.is_some() {
+            out.push_str(&text);
+        } else {
+            out.push_str(&escape(&text));
+        }
+    }
+    out
+}
+
+/// Substitutes the references found in `vars` and the environment and keeps the
+/// others as written. Unlike `render`, the result is a plain value, so `{{` and `}}`
+/// become literal braces.
+pub fn substitute(input: &str, vars: &Vars) -> String {
+    parse(input)
+        .into_iter()
+        .map(|token| match token {
+            Token::Text(text) => text,
+            Token::Ref(reference) => reference
+                .resolve(vars, &var::process_env, None)
+                .unwrap_or_else(|| reference.source()),
+        })
+        .collect()
+}
+
+/// Evaluates the built-ins and defaults of `input`, a value rather than a template,
+/// and substitutes the references found in `vars`. Everything else, literal braces
+/// included, is kept as written.
+pub fn evaluate(input: &str, vars: &Vars, builtins: &Builtins) -> String {
+    tokenize(input)
+        .into_iter()
+        .map(|(token, source)| match token {
+            Token::Text(_) => source,
+            Token::Ref(reference) => reference
+                .resolve(vars, &var::process_env, Some(builtins))
+                .unwrap_or(source),
+        })
+        .collect()
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('{', "{{").replace('}', "}}")
+}
+
+fn is_name(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+pub fn urlencode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod template_tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn names(input: &str) -> Vec<String> {
+        references(input).into_iter().map(|x| x.raw).collect()
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vars {
+        Vars(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<IndexMap<String, String>>(),
+        )
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(vec!["name"], names("{name}"));
+        assert_eq!(vec!["a", "b", "c", "d"], names("{a} {b} {c} {{{d}}}"));
+        assert_eq!(vec!["real"], names("{{not_a_var}} and {real}"));
+        assert!(names("").is_empty());
+        assert!(names("hello world").is_empty());
+        assert!(names("oops}").is_empty());
+        assert!(names("{{}}").is_empty());
+        assert!(names("{hello world}").is_empty());
+        assert_eq!(
+            vec!["user:-guest|upper", "env:HOME", "PATH"],
+            names("{user:-guest|upper} {env:HOME} ${PATH}")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let reference = |raw: &str, shell: bool| Token::Ref(Reference::new(raw.to_string(), shell));
+        assert_eq!(
+            vec![
+                Token::Text("{a} ".to_string()),
+                reference("b:-x|upper| json", false),
+                Token::Text(" ".to_string()),
+                reference("HOME", true),
+                Token::Text(" {c".to_string()),
+            ],
+            parse("{{a}} {b:-x|upper| json} ${HOME} {c")
+        );
+        let Token::Ref(r) = reference("b:-x|upper| json", false) else {
+            unreachable!()
+        };
+        assert_eq!("b", r.key);
+        assert_eq!(Some("x".to_string()), r.default);
+        assert_eq!(vec!["upper", "json"], r.filters);
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = vars(&[
+            ("a", "{b}"),
+            ("b", "B"),
+            ("q", "a b&c"),
+            ("s", "say \"hi\""),
+        ]);
+        // values are not expanded again and escapes become literal braces
+        assert_eq!(
+            "{b} B {b}",
+            render_final("{a} {b} {{b}}", &vars, &Builtins::default())
+        );
+        assert_eq!(
+            "a%20b%26c A B&C \"say \\\"hi\\\"\"",
+            render_final(
+                "{q|urlencode} {q|upper} {s|json}",
+                &vars,
+                &Builtins::default()
+            )
+        );
+        assert_eq!(
+            "none {missing}",
+            render_final("{missing:-none} {missing}", &vars, &Builtins::default())
+        );
+        assert_eq!(
+            "function() { return 1; }",
+            render_final("function() { return 1; }", &vars, &Builtins::default())
+        );
+        assert_eq!(
+            "{a|reverse}",
+            render_final("{a|reverse}", &vars, &Builtins::default())
+        );
+    }
+
+    #[test]
+    fn test_substitute() {
+        let vars = vars(&[("host", "example.com"), ("json", "{a}")]);
+        assert_eq!(
+            "https://example.com/{path} {json} {a} {$uuid} {host}",
+            substitute(
+                "https://{host}/{path} {{json}} {json} {$uuid} {{host}}",
+                &vars
+            )
+        );
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let vars = vars(&[("host", "example.com")]);
+        let value = evaluate(
+            "{\"to\": \"u-{$random_int:1:1}@{host}\"}} {{ {role:-guest}",
+            &vars,
+            &Builtins::default(),
+        );
+        assert_eq!("{\"to\": \"u-1@example.com\"}} {{ guest", value);
+    }
+
+    #[test]
+    fn test_builtin() {
+        let builtin = |raw: &str| {
+            let reference = Reference::new(raw.to_string(), false);
+            reference
+                .builtin()
+                .map(|(name, args)| (name.to_string(), args.join(",")))
+        };
+        assert_eq!(Some(("uuid".to_string(), String::new())), builtin("$uuid"));
+        assert_eq!(
+            Some(("random_int".to_string(), "-5,5".to_string())),
+            builtin("$random_int:-5:5")
+        );
+        assert_eq!(
+            Some(("date".to_string(), "%H:%M:%S".to_string())),
+            builtin("$date:%H:%M:%S")
+        );
+        assert_eq!(None, builtin("date:%H"));
+
+        let time = render_final(
+            "{$date:%H:%M}",
+            &Vars(IndexMap::new()),
+            &Builtins::default(),
+        );
+        assert_eq!(5, time.len());
+        assert_eq!(Some(':'), time.chars().nth(2));
+    }
+
+    #[test]
+    fn test_render_in_passes() {
+        let first = vars(&[("a", "{b}")]);
+        let second = vars(&[("b", "B"), ("c", "C")]);
+        let template = render("{a} {c} {{c}} {d:-x} {$step_index}", &first);
+        assert_eq!("{{b}} {c} {{c}} {d:-x} {$step_index}", template);
+        assert_eq!(
+            "{b} C {c} x 0",
+            render_final(&template, &second, &Builtins::default())
+        );
+    }
+}
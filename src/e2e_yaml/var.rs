@@ -1,111 +1,407 @@
-use indexmap::IndexMap;
+use std::path::Path;
+
+use indexmap::{IndexMap, IndexSet};
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Debug, Deserialize, Serialize)]
+use super::builtin::Builtins;
+use super::template;
+
+#[derive(Debug, PartialEq)]
+pub struct VarError {
+    pub kind: VarErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VarErrorKind {
+    CircularReferences(Vec<String>),
+    /// Holds the path of the file and the reason.
+    InvalidFile(String, String),
+}
+
+impl std::error::Error for VarError {}
+
+impl std::fmt::Display for VarError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            VarErrorKind::CircularReferences(path) => {
+                writeln!(
+                    f,
+                    "circular references detected in vars. {}",
+                    path.join(" -> ")
+                )
+            }
+            VarErrorKind::InvalidFile(path, reason) => {
+                writeln!(f, "invalid var file '{}'. {}", path, reason)
+            }
+        }
+    }
+}
+
+/// Parts of names whose values are hidden when vars are printed.
+const SENSITIVE: &[&str] = &[
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "api_key",
+    "apikey",
+    "credential",
+    "private_key",
+];
+
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct Vars(pub IndexMap<String, String>);
 
-pub fn parse_var_names(input: &str) -> Option<Vec<String>> {
-    let mut buf: Option<String> = None;
-    let mut result: Vec<String> = Vec::new();
-    let mut peekable = input.chars().peekable();
-    while let Some(c) = peekable.next() {
-        match c {
-            '{' => {
-                // escape
-                if let Some(&'{') = peekable.peek() {
-                    peekable.next();
-                    continue;
-                }
-                buf = Some(String::new());
+/// Reads an environment variable, injected so that tests do not depend on the
+/// environment of the process.
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Reads the environment of the process.
+pub fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+impl Vars {
+    /// Variables of the process environment.
+    pub fn from_env() -> Vars {
+        Vars(std::env::vars().collect())
+    }
+
+    /// Loads a YAML mapping, or a `.env` file if the extension is neither `.yaml` nor `.yml`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Vars, VarError> {
+        let path = path.as_ref();
+        let invalid = |reason: String| VarError {
+            kind: VarErrorKind::InvalidFile(path.display().to_string(), reason),
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&contents).map_err(|e| invalid(e.to_string()))
             }
-            '}' => {
-                // escape
-                if let Some(&'}') = peekable.peek() {
-                    peekable.next();
+            _ => parse_env(&contents).map_err(invalid),
+        }
+    }
+
+    /// Returns a copy of `self` with the values of sensitive names replaced with `***`.
+    pub fn masked(&self) -> Vars {
+        Vars(
+            self.0
+                .iter()
+                .map(|(k, v)| match is_sensitive(k) {
+                    true => (k.to_string(), "***".to_string()),
+                    false => (k.to_string(), v.to_string()),
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns a copy of `self` with the entries of `other` taking precedence.
+    pub fn merged(&self, other: &Vars) -> Vars {
+        let mut result = self.clone();
+        result.0.extend(other.0.clone());
+        result
+    }
+
+    /// Returns a copy of `self` without the entries named in `names`.
+    pub fn without(&self, names: &IndexSet<String>) -> Vars {
+        let mut result = self.clone();
+        result.0.retain(|k, _| !names.contains(k));
+        result
+    }
+
+    /// Substitutes the references between the entries of `self` in dependency order.
+    /// References to other names, and of an entry to itself, are looked up in `outer`,
+    /// and kept as is if missing. Built-ins and defaults are kept, and so are the
+    /// references to the entries holding them, until the entries are bound.
+    pub fn resolve(&self, outer: &Vars) -> Result<Vars, VarError> {
+        self.resolve_with(outer, None)
+    }
+
+    /// Evaluates the built-ins and defaults left by `resolve`, and substitutes the
+    /// entries holding them in the others. Bound once per scenario, so that all of
+    /// its steps get the same values.
+    pub fn bind(&self, builtins: &Builtins) -> Result<Vars, VarError> {
+        let unbound = self.unbound();
+        let values = Vars(
+            self.0
+                .iter()
+                .filter(|(k, _)| unbound.contains(*k))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+        let bound = values.resolve_with(&Vars(IndexMap::new()), Some(builtins))?;
+        Ok(self.merged(&bound))
+    }
+
+    /// Names of the entries holding built-ins, defaults or references to such entries.
+    pub fn unbound(&self) -> IndexSet<String> {
+        let mut result: IndexSet<String> = IndexSet::new();
+        loop {
+            let len = result.len();
+            for (key, value) in &self.0 {
+                if result.contains(key) {
                     continue;
                 }
-                if buf.is_some() {
-                    result.push(buf.clone().unwrap());
+                let is_unbound = template::references(value).iter().any(|x| {
+                    x.builtin().is_some()
+                        || x.default.is_some()
+                        || (x.key != *key && result.contains(&x.key))
+                });
+                if is_unbound {
+                    result.insert(key.to_string());
                 }
             }
-            _ => {
-                if let Some(ref mut b) = buf {
-                    b.push(c);
-                }
+            if result.len() == len {
+                return result;
             }
         }
     }
 
-    if result.is_empty() {
-        None
-    } else {
-        Some(result)
+    fn resolve_with(&self, outer: &Vars, builtins: Option<&Builtins>) -> Result<Vars, VarError> {
+        let mut resolved = Vars(IndexMap::new());
+        for key in self.0.keys() {
+            let mut visiting: IndexSet<&str> = IndexSet::new();
+            self.resolve_recursive(key, outer, builtins, &mut visiting, &mut resolved)?;
+        }
+        // the order of declaration is kept
+        Ok(Vars(
+            self.0
+                .keys()
+                .map(|k| (k.to_string(), resolved.0[k].to_string()))
+                .collect(),
+        ))
     }
+
+    fn resolve_recursive<'a>(
+        &'a self,
+        key: &'a str,
+        outer: &Vars,
+        builtins: Option<&Builtins>,
+        visiting: &mut IndexSet<&'a str>,
+        resolved: &mut Vars,
+    ) -> Result<(), VarError> {
+        if resolved.0.contains_key(key) {
+            return Ok(());
+        }
+        if !visiting.insert(key) {
+            let mut path: Vec<String> = visiting.iter().map(|x| x.to_string()).collect();
+            path.push(key.to_string());
+            return Err(VarError {
+                kind: VarErrorKind::CircularReferences(path),
+            });
+        }
+        let value = &self.0[key];
+        for dep in self.dependencies(value).into_iter().filter(|x| *x != key) {
+            self.resolve_recursive(dep, outer, builtins, visiting, resolved)?;
+        }
+        let value = match builtins {
+            // the other references were substituted by `resolve`, those left are
+            // literal braces of the plain value
+            Some(builtins) => template::evaluate(value, resolved, builtins),
+            None => {
+                let scope = outer.merged(resolved);
+                template::substitute(value, &scope.without(&scope.unbound()))
+            }
+        };
+        resolved.0.insert(key.to_string(), value);
+        visiting.pop();
+        Ok(())
+    }
+
+    /// Entries of `self` referenced by `value`.
+    fn dependencies(&self, value: &str) -> Vec<&str> {
+        template::references(value)
+            .iter()
+            .filter(|x| x.builtin().is_none())
+            .filter_map(|x| self.0.get_key_value(&x.key))
+            .map(|(k, _)| k.as_str())
+            .collect()
+    }
+}
+
+/// Parses `NAME=VALUE` lines. Empty lines, `#` comments and a leading `export`
+/// are skipped, and quotes around the value are removed.
+fn parse_env(contents: &str) -> Result<Vars, String> {
+    let mut vars = Vars(IndexMap::new());
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+            _ => return Err(format!("expected NAME=VALUE at line {}", i + 1)),
+        };
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|q| value.strip_prefix(*q).and_then(|x| x.strip_suffix(*q)))
+            .unwrap_or(value);
+        vars.0.insert(name.to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE.iter().any(|x| name.contains(x))
 }
 
 #[cfg(test)]
 mod var_tests {
     use super::*;
 
-    #[test]
-    fn test_single_variable() {
-        let input = "{name}";
-        let expected = Some(vec!["name".to_string()]);
-        assert_eq!(parse_var_names(input), expected);
+    fn vars(pairs: &[(&str, &str)]) -> Vars {
+        Vars(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
     }
 
     #[test]
-    fn test_multiple_variables() {
-        let input = "{a} {b} {c} {{{d}}}";
-        let expected = Some(vec![
-            "a".to_string(),
-            "b".to_string(),
-            "c".to_string(),
-            "d".to_string(),
-        ]);
-        assert_eq!(parse_var_names(input), expected);
-    }
+    fn test_resolve() {
+        let outer = vars(&[("scheme", "https"), ("user", "outer")]);
+        let actual = vars(&[
+            ("api", "{root}/api/v1"),
+            ("root", "{scheme}://{host}"),
+            ("host", "example.com"),
+            ("user", "admin"),
+            ("account", "{user|upper}@{host} {order} {$uuid} {{host}}"),
+        ])
+        .resolve(&outer)
+        .unwrap();
+        assert_eq!(
+            vars(&[
+                ("api", "https://example.com/api/v1"),
+                ("root", "https://example.com"),
+                ("host", "example.com"),
+                ("user", "admin"),
+                ("account", "ADMIN@example.com {order} {$uuid} {host}"),
+            ]),
+            actual
+        );
+        assert_eq!(IndexSet::from(["account".to_string()]), actual.unbound());
 
-    #[test]
-    fn test_escaped_braces() {
-        let input = "{{not_a_var}} and {real}";
-        let expected = Some(vec!["real".to_string()]);
-        assert_eq!(parse_var_names(input), expected);
+        // built-ins are evaluated when bound, literal braces are kept
+        let actual = actual.bind(&Builtins::default()).unwrap();
+        let account = &actual.0["account"];
+        assert!(account.starts_with("ADMIN@example.com {order} "));
+        assert!(account.ends_with(" {host}"));
+        assert!(!account.contains("$uuid"));
     }
 
     #[test]
-    fn test_empty_input() {
-        let input = "";
-        let expected = None;
-        assert_eq!(parse_var_names(input), expected);
+    fn test_bind() {
+        let outer = vars(&[("host", "example.com")]);
+        let resolved = vars(&[
+            ("email", "{id}@{host}"),
+            ("id", "u-{$random_int:7:7}"),
+            ("lang", "{lang:-en}"),
+            ("url", "{host}/users/{email}"),
+        ])
+        .resolve(&outer)
+        .unwrap();
+        // references to unbound entries are kept until they are bound
+        assert_eq!(
+            vars(&[
+                ("email", "{id}@example.com"),
+                ("id", "u-{$random_int:7:7}"),
+                ("lang", "{lang:-en}"),
+                ("url", "example.com/users/{email}"),
+            ]),
+            resolved
+        );
+        assert_eq!(
+            Ok(vars(&[
+                ("email", "u-7@example.com"),
+                ("id", "u-7"),
+                ("lang", "en"),
+                ("url", "example.com/users/u-7@example.com"),
+            ])),
+            resolved.bind(&Builtins::default())
+        );
     }
 
     #[test]
-    fn test_no_variables() {
-        let input = "hello world";
-        let expected = None;
-        assert_eq!(parse_var_names(input), expected);
+    fn test_parse_env() {
+        let contents = "
+# staging
+ROOT=https://staging.example.com
+export USER_NAME = admin
+GREETING=\"hello world\"
+QUOTE='a=b'
+EMPTY=
+";
+        assert_eq!(
+            Ok(vars(&[
+                ("ROOT", "https://staging.example.com"),
+                ("USER_NAME", "admin"),
+                ("GREETING", "hello world"),
+                ("QUOTE", "a=b"),
+                ("EMPTY", ""),
+            ])),
+            parse_env(contents)
+        );
+        assert_eq!(
+            Err("expected NAME=VALUE at line 2".to_string()),
+            parse_env("A=1\nB")
+        );
     }
 
     #[test]
-    fn test_trailing_brace_without_opening() {
-        let input = "oops}";
-        let expected = None;
-        assert_eq!(parse_var_names(input), expected);
+    fn test_masked() {
+        assert_eq!(
+            vars(&[
+                ("user", "admin"),
+                ("password", "***"),
+                ("GITHUB_TOKEN", "***"),
+                ("stripe_api_key", "***"),
+            ]),
+            vars(&[
+                ("user", "admin"),
+                ("password", "passw0rd"),
+                ("GITHUB_TOKEN", "ghp_x"),
+                ("stripe_api_key", "sk_test"),
+            ])
+            .masked()
+        );
     }
 
     #[test]
-    fn test_escape_sequence_only() {
-        let input = "{{}}";
-        let expected = None;
-        assert_eq!(parse_var_names(input), expected);
+    fn test_circular_references() {
+        let res = vars(&[("a", "{b}"), ("b", "{c}/x"), ("c", "{a}"), ("d", "d")])
+            .resolve(&Vars(IndexMap::new()))
+            .err();
+        assert_eq!(
+            Some(VarError {
+                kind: VarErrorKind::CircularReferences(vec![
+                    "a".to_string(),
+                    "b".to_string(),
+                    "c".to_string(),
+                    "a".to_string()
+                ])
+            }),
+            res
+        );
     }
 
     #[test]
-    fn test_whitespace_inside_var() {
-        let input = "{hello world}";
-        let expected = Some(vec!["hello world".to_string()]);
-        assert_eq!(parse_var_names(input), expected);
+    fn test_resolve_self_reference() {
+        let global = vars(&[("root", "http://localhost")]);
+        let res = vars(&[("api", "{root}/api"), ("root", "{root}/staging")]).resolve(&global);
+        assert_eq!(
+            Ok(vars(&[
+                ("api", "http://localhost/staging/api"),
+                ("root", "http://localhost/staging")
+            ])),
+            res
+        );
+
+        // kept as is when the outer scope does not define it
+        let res = vars(&[("path", "{path}/bin")]).resolve(&Vars(IndexMap::new()));
+        assert_eq!(Ok(vars(&[("path", "{path}/bin")])), res);
     }
 }
@@ -1,8 +1,11 @@
+use std::path::Path;
 use std::process::{self, ExitCode};
 
 use clap::Parser;
-use cli::Args;
+use cli::{Args, Cmd};
+use e2e_yaml::strict::{self, StrictError, StrictErrorKind};
 use e2e_yaml::task::Tasks;
+use e2e_yaml::E2eYaml;
 
 mod cli;
 mod e2e_yaml;
@@ -10,7 +13,56 @@ mod e2e_yaml;
 #[tokio::main]
 async fn main() -> std::result::Result<ExitCode, Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let e2e_yaml = e2e_yaml::load_e2e_yaml_from_file(&args.file)?.expand();
+    let exit_code = match &args.cmd {
+        Cmd::Run(run) => run.run(&args.file, load(&args)?).await?,
+        Cmd::Config(config) => {
+            config.run(&load(&args)?);
+            0
+        }
+        // managing the secrets file does not need a valid configuration
+        Cmd::Secrets(secrets) => {
+            if let Err(e) = secrets.run() {
+                eprintln!("{}", e.to_string().trim_end());
+                process::exit(1);
+            }
+            0
+        }
+    };
+
+    Ok(ExitCode::from(exit_code))
+}
+
+/// Loads the configuration and checks it, exiting on the first error.
+fn load(args: &Args) -> Result<E2eYaml, Box<dyn std::error::Error>> {
+    let base_dir = args.file.parent().unwrap_or(Path::new(""));
+    let overrides = match args.overrides() {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprint!("{}", e);
+            process::exit(1);
+        }
+    };
+    let mut e2e_yaml = e2e_yaml::load_e2e_yaml_from_file(&args.file)?;
+    if let Some(profile) = &args.profile {
+        e2e_yaml = match e2e_yaml.apply_profile(profile) {
+            Ok(e2e_yaml) => e2e_yaml,
+            Err(e) => {
+                eprint!("{}", e);
+                process::exit(1);
+            }
+        };
+    }
+    let e2e_yaml = match e2e_yaml.load_secrets(base_dir) {
+        Ok(e2e_yaml) => e2e_yaml,
+        Err(e) => {
+            eprint!("{}", e);
+            process::exit(1);
+        }
+    };
+    let e2e_yaml = e2e_yaml
+        .instantiate(base_dir)?
+        .resolve_vars(&overrides)?
+        .expand();
 
     if let Some(Err(e)) = e2e_yaml
         .tasks
@@ -20,8 +72,26 @@ async fn main() -> std::result::Result<ExitCode, Box<dyn std::error::Error>> {
         eprintln!("{}", e);
         process::exit(1);
     }
+    if let Err(e) = e2e_yaml.validate_task_calls() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Err(e) = e2e_yaml.validate_snapshot_names() {
+        eprint!("{}", e);
+        process::exit(1);
+    }
+    let undefined = strict::find_undefined_vars(&e2e_yaml);
+    if !undefined.is_empty() {
+        let e = StrictError {
+            kind: StrictErrorKind::UndefinedVars(args.file.display().to_string(), undefined),
+        };
+        if args.allow_undefined_vars {
+            eprint!("warning: {}", e);
+        } else {
+            eprint!("{}", e);
+            process::exit(1);
+        }
+    }
 
-    let exit_code = args.cmd.run(e2e_yaml).await?;
-
-    Ok(ExitCode::from(exit_code))
+    Ok(e2e_yaml)
 }